@@ -2,43 +2,44 @@
 //! If you want to use RProbe in your bluespec project,
 //! please compile this crate into an .a file and then link it to your bluesim executable.
 #![warn(clippy::unwrap_used)]
-use rb_link::{B2RMessage, GetPutMessage, MsgSizeType, MSG_SIZE_BYTES};
+use rb_link::{
+    B2RMessage, GetPutMessage, MsgSizeType, CYCLE_END_ACK, CYCLE_END_STOP, MSG_SIZE_BYTES,
+};
 use std::env;
 use std::io::{Read, Write};
 use std::os::unix::net::UnixStream;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 use std::sync::OnceLock;
 
-static mut STREAM: OnceLock<UnixStream> = OnceLock::new();
+static STREAM: OnceLock<UnixStream> = OnceLock::new();
+// the cycle of the last message, u32::MAX before the first message
+static LAST_CYCLE: AtomicU32 = AtomicU32::new(u32::MAX);
+// the server requested the simulation to stop
+static STOP_REQUESTED: AtomicBool = AtomicBool::new(false);
+static SHUT_DOWN: AtomicBool = AtomicBool::new(false);
 
 /// # Safety
 /// This function should not be called by Rust code.
 /// Get data from your rust program.
 /// called by RProbe::get_data()
+/// the data is zeroed after shut_down()
 #[no_mangle]
-pub unsafe extern "C" fn get(res_ptr: *mut u8, id: u32, _cycles: u32, size: u32) {
+pub unsafe extern "C" fn get(res_ptr: *mut u8, id: u32, cycles: u32, size: u32) {
     // println!("send get");
     // check the ptr is not null
     if res_ptr.is_null() {
         panic!("res_ptr is a null pointer!");
     }
-    if _cycles == u32::MAX {
+    if cycles == u32::MAX {
         panic!("cycles over flow!");
     }
-    let mut stream = STREAM.get_or_init(get_stream);
-
-    let get_message = GetPutMessage::Get(id);
-    let serialized = bincode::serialize(&get_message).expect("Serialization failed");
-
-    // The initial 4-byte data specifies the byte count of the message in the u32 format.
-    let msg_size = serialized.len() as MsgSizeType;
-    let mut msg_with_size = Vec::with_capacity(MSG_SIZE_BYTES + serialized.len());
-    msg_with_size.extend_from_slice(&msg_size.to_le_bytes());
-    msg_with_size.extend(serialized.iter());
-    stream
-        .write_all(&msg_with_size)
-        .expect("Failed to write to stream");
-
     let res_slice = std::slice::from_raw_parts_mut(res_ptr, size as usize);
+    if SHUT_DOWN.load(Ordering::Acquire) {
+        res_slice.fill(0);
+        return;
+    }
+    end_cycle_before(cycles);
+    let mut stream = send_getput(&GetPutMessage::Get(id, cycles));
     stream
         .read_exact(res_slice)
         .expect("Failed to read from stream");
@@ -48,6 +49,7 @@ pub unsafe extern "C" fn get(res_ptr: *mut u8, id: u32, _cycles: u32, size: u32)
 /// This function should not be called by Rust code.
 /// Put data to your rust program.
 /// called by RProbe::put_data()
+/// the data is dropped after shut_down()
 #[no_mangle]
 pub unsafe extern "C" fn put(id: u32, cycles: u32, data_ptr: *mut u8, size: u32) {
     // println!("send put");
@@ -58,38 +60,107 @@ pub unsafe extern "C" fn put(id: u32, cycles: u32, data_ptr: *mut u8, size: u32)
     if cycles == u32::MAX {
         panic!("cycles over flow!");
     }
+    if SHUT_DOWN.load(Ordering::Acquire) {
+        return;
+    }
 
-    let mut stream = STREAM.get_or_init(get_stream);
-
+    end_cycle_before(cycles);
     let data_slice = std::slice::from_raw_parts(data_ptr, size as usize);
     let b2r_message = B2RMessage {
         id,
         cycles,
         message: data_slice.to_vec(),
     };
-    let put_message = GetPutMessage::Put(b2r_message);
-    let serialized = bincode::serialize(&put_message).expect("Serialization failed");
+    send_getput(&GetPutMessage::Put(b2r_message));
+}
 
-    // The initial 4-byte data specifies the byte count of the message in the u32 format.
-    let msg_size = serialized.len() as MsgSizeType;
-    let mut msg_with_size = Vec::with_capacity(MSG_SIZE_BYTES + serialized.len());
-    msg_with_size.extend_from_slice(msg_size.to_le_bytes().as_slice());
-    msg_with_size.extend(serialized.iter());
+/// # Safety
+/// This function should not be called by Rust code.
+/// no more message to send,send a shut down message to the server
+/// only the first call sends the message
+#[no_mangle]
+pub unsafe extern "C" fn shut_down() {
+    if SHUT_DOWN.swap(true, Ordering::AcqRel) {
+        return;
+    }
+    let last_cycle = LAST_CYCLE.load(Ordering::Acquire);
+    if last_cycle != u32::MAX {
+        end_cycle(last_cycle);
+    }
+    send_getput(&GetPutMessage::ShutDown);
+}
 
+/// # Safety
+/// This function should not be called by Rust code.
+/// Try to get data from your rust program without blocking.
+/// called by RProbe::try_get_data()
+/// res_ptr points to size + 1 bytes, the first byte is 1 if the data is valid,
+/// the data is written to the following size bytes.
+/// the data is invalid after shut_down()
+#[no_mangle]
+pub unsafe extern "C" fn try_get(res_ptr: *mut u8, id: u32, cycles: u32, size: u32) {
+    // check the ptr is not null
+    if res_ptr.is_null() {
+        panic!("res_ptr is a null pointer!");
+    }
+    if cycles == u32::MAX {
+        panic!("cycles over flow!");
+    }
+    let res_slice = std::slice::from_raw_parts_mut(res_ptr, size as usize + 1);
+    if SHUT_DOWN.load(Ordering::Acquire) {
+        res_slice[0] = 0;
+        return;
+    }
+    end_cycle_before(cycles);
+    let mut stream = send_getput(&GetPutMessage::TryGet(id, cycles));
     stream
-        .write_all(&msg_with_size)
-        .expect("Failed to write to stream");
+        .read_exact(&mut res_slice[..1])
+        .expect("Failed to read from stream");
+    if res_slice[0] != 0 {
+        stream
+            .read_exact(&mut res_slice[1..])
+            .expect("Failed to read from stream");
+    }
 }
 
 /// # Safety
 /// This function should not be called by Rust code.
-/// no more message to send,send a shut down message to the server
+/// return 1 if the rust program requested the simulation to stop
+/// called by the stop rule of RProbe
 #[no_mangle]
-pub unsafe extern "C" fn shut_down() {
-    let mut stream = STREAM.get_or_init(get_stream);
+pub unsafe extern "C" fn stop_requested() -> u8 {
+    STOP_REQUESTED.load(Ordering::Acquire) as u8
+}
 
-    let put_message = GetPutMessage::ShutDown;
-    let serialized = bincode::serialize(&put_message).expect("Serialization failed");
+/// Report the last cycle complete if the message of a new cycle comes.
+fn end_cycle_before(cycles: u32) {
+    let last_cycle = LAST_CYCLE.load(Ordering::Acquire);
+    if last_cycle == u32::MAX || cycles > last_cycle {
+        LAST_CYCLE.store(cycles, Ordering::Release);
+    }
+    if last_cycle != u32::MAX && cycles > last_cycle {
+        end_cycle(last_cycle);
+    }
+}
+
+/// Send a CycleEnd message and wait for the server to process the cycle.
+fn end_cycle(cycles: u32) {
+    let mut stream = send_getput(&GetPutMessage::CycleEnd(cycles));
+    let mut ack = [0];
+    stream
+        .read_exact(&mut ack)
+        .expect("Failed to read from stream");
+    match ack[0] {
+        CYCLE_END_ACK => {}
+        CYCLE_END_STOP => STOP_REQUESTED.store(true, Ordering::Release),
+        _ => panic!("unknown cycle end answer"),
+    }
+}
+
+/// Send a message to the server, return the stream to read the response.
+fn send_getput(message: &GetPutMessage) -> &'static UnixStream {
+    let mut stream = STREAM.get_or_init(get_stream);
+    let serialized = bincode::serialize(message).expect("Serialization failed");
 
     // The initial 4-byte data specifies the byte count of the message in the u32 format.
     let msg_size = serialized.len() as MsgSizeType;
@@ -100,6 +171,7 @@ pub unsafe extern "C" fn shut_down() {
     stream
         .write_all(&msg_with_size)
         .expect("Failed to write to stream");
+    stream
 }
 
 fn get_stream() -> UnixStream {
@@ -109,3 +181,40 @@ fn get_stream() -> UnixStream {
     };
     UnixStream::connect(socket).expect("Failed to connect to socket")
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rb_link::{B2RServer, IDGetter};
+
+    #[test]
+    fn test_put_after_shut_down() {
+        env::set_var("B2R_SOCKET", "/tmp/test_put_after_shut_down");
+        let mut server = B2RServer::new_with("/tmp/test_put_after_shut_down");
+        let mut id_getter = IDGetter::new(&server);
+        let handle = server.serve();
+
+        let mut data = [0x11u8, 0x22];
+        unsafe {
+            put(1, 0, data.as_mut_ptr(), 2);
+            // the stop rule calls shut_down() in the same cycle as the rules of the other probes
+            shut_down();
+            put(1, 0, data.as_mut_ptr(), 2);
+        }
+        handle.join().expect("Fail to join the server thread");
+
+        unsafe {
+            put(1, 1, data.as_mut_ptr(), 2);
+            let mut res = [0xffu8; 2];
+            get(res.as_mut_ptr(), 2, 1, 2);
+            assert_eq!(res, [0, 0]);
+            let mut res = [0xffu8; 3];
+            try_get(res.as_mut_ptr(), 2, 1, 2);
+            assert_eq!(res[0], 0);
+        }
+
+        let msg = id_getter.get(1);
+        assert_eq!(msg.message, vec![0x11, 0x22]);
+        assert!(id_getter.try_get(1).is_none());
+    }
+}
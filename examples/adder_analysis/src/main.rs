@@ -16,18 +16,13 @@ fn main() {
 
     thread::sleep(Duration::from_secs(5));
  
+    // the pipeline adds 1 to the inputs
+    let mut scoreboard = Scoreboard::with_inputs(0, 0..10u32, |data: u32| data + 1);
     let msg_vec = id_getter.get_id_all(0);
     for msg in msg_vec {
-        println!(
-            "get from blue id:{}, cycle:{}, data:{}",
-            msg.id,
-            msg.cycles,
-            u32::from_le_bytes([
-                msg.message[0],
-                msg.message[1], 
-                msg.message[2],
-                msg.message[3]
-            ])
-        );
+        if let Some(mismatch) = scoreboard.record(&msg) {
+            println!("{}", mismatch);
+        }
     }
+    print!("{}", scoreboard);
 }
@@ -6,17 +6,17 @@ fn main() {
     let input_data: Vec<u32> = (1..200).collect();
 
     let mut server = B2RServer::new_with("/tmp/ten_stage");
+    // bluesim waits for the analyzer instead of filling the memory
+    server.set_default_capacity(4096, OverflowPolicy::Block);
     let mut pipe_getter = PipeLineGetter::new(&server);
 
-    // marked probes
-    for i in 0..9 {
-        let id: u32 = i;
-        pipe_getter.add_fifo_probe(id);
-    }
-    for i in 10..19 {
-        let id: u32 = i;
-        pipe_getter.add_rule_probe(id);
+    // stage i + 1 (rule 10 + i) reads fifo i - 1 and writes fifo i
+    let mut topology = PipelineTopology::new().stage("stage1", 10, &[], &[0]);
+    for i in 1..9 {
+        topology.add_stage(&format!("stage{}", i + 1), 10 + i, &[i - 1], &[i]);
     }
+    // marked probes
+    topology.add_probes_to(&mut pipe_getter);
 
     // set input
     for i in input_data {
@@ -27,27 +27,21 @@ fn main() {
 
     thread::sleep(Duration::from_secs(5));
 
-    let mut _fired: u32 = 0;
+    let mut detector = DeadlockDetector::with_topology(10, topology.clone());
+    let mut stats = PipelineStats::from_topology(&topology);
+    let mut stalls = StallAttribution::new(topology);
+
     loop {
         let state = pipe_getter.get_pipeline_state();
-        // if rules fired in cycle i+1 > if rules fired in cycle i the pipeline is not stuck
-        if state.fire_rules.len() as u32 > _fired {
-            _fired = state.fire_rules.len() as u32;
-        } else if state.empty_fifos.len() + state.full_fifos.len() == 9 { // if all fifos is empty or full the pipeline is stuck
-            println!("pipeline stuck at cycle: {}", state.cycle);
-            print!("full fifos:");
-            for id in state.full_fifos {
-                print!(" {} ", id);
-            }
-            print!("\nempty fifos:");
-            for id in state.empty_fifos {
-                print!(" {} ", id);
-            }
-            print!("\nfired rules:");
-            for id in state.fire_rules {
-                print!(" {}  ", id)
-            }
-            print!("\n");
+        stalls.update(&state);
+        stats.update(&state);
+        if let Some(report) = detector.update(&state) {
+            print!("{}", report);
+            print!("{}", stalls);
+            let summary = stats.summary();
+            print!("{}", summary);
+            std::fs::write("ten_stage_stats.json", summary.to_json())
+                .expect("Fail to write the statistics");
             break;
         }
     }
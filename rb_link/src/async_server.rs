@@ -0,0 +1,474 @@
+//! The async server on the tokio runtime, enabled by the "async" feature.
+//!
+//! It supports a smaller feature set than B2RServer: the messages are read by get(), try_get()
+//! or the streams, the queues and the streams are bounded by set_capacity() and set_default_capacity().
+//! put_at(), put_after(), the sources, the handlers, the invalid responses,
+//! the message stores and the getters are only available on B2RServer.
+use crate::config::*;
+use crate::server::{update_cycle, B2RMessage, Capacity, GetPutMessage, ProbeQueue, Push};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::io::{Error, ErrorKind};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll, Waker};
+use std::{fs, io};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+use tokio_stream::Stream;
+
+/// The capacity of a stream without a capacity set by set_capacity() or set_default_capacity().
+const DEFAULT_STREAM_CAPACITY: Capacity = Capacity {
+    capacity: 1024,
+    policy: OverflowPolicy::Block,
+};
+
+struct StreamState<T> {
+    items: VecDeque<T>,
+    waker: Option<Waker>,
+    finished: bool,
+}
+
+/// The items of a stream pushed by the server, bounded by the capacity of the stream.
+struct StreamQueue<T> {
+    state: Mutex<StreamState<T>>,
+    capacity: Capacity,
+    // notify the server waiting for a full queue
+    space: Notify,
+    // the stream is dropped
+    closed: AtomicBool,
+}
+
+impl<T> StreamQueue<T> {
+    fn new(capacity: Capacity) -> Self {
+        StreamQueue {
+            state: Mutex::new(StreamState {
+                items: VecDeque::new(),
+                waker: None,
+                finished: false,
+            }),
+            capacity,
+            space: Notify::new(),
+            closed: AtomicBool::new(false),
+        }
+    }
+
+    /// Push an item by the policy of the stream,
+    /// wait for the stream while the queue is full if the policy is Block or SpillToDisk.
+    /// return false if an item is dropped
+    async fn push(&self, item: T) -> bool {
+        let mut item = Some(item);
+        loop {
+            let space = self.space.notified();
+            tokio::pin!(space);
+            space.as_mut().enable();
+            {
+                let mut state = lock(&self.state);
+                if self.closed.load(Ordering::Acquire) {
+                    return true;
+                }
+                let mut pushed = true;
+                if state.items.len() >= self.capacity.capacity {
+                    match self.capacity.policy {
+                        OverflowPolicy::DropOldest => {
+                            state.items.pop_front();
+                            pushed = false;
+                        }
+                        OverflowPolicy::DropNewest => return false,
+                        OverflowPolicy::Block | OverflowPolicy::SpillToDisk => {}
+                    }
+                }
+                if state.items.len() < self.capacity.capacity {
+                    state.items.extend(item.take());
+                    if let Some(waker) = state.waker.take() {
+                        waker.wake();
+                    }
+                    return pushed;
+                }
+            }
+            space.await;
+        }
+    }
+
+    fn finish(&self) {
+        let mut state = lock(&self.state);
+        state.finished = true;
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// A stream of the items pushed by the server, ends when Bluesim shuts down the server.
+struct QueueStream<T>(Arc<StreamQueue<T>>);
+
+impl<T> Stream for QueueStream<T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        let mut state = lock(&self.0.state);
+        if let Some(item) = state.items.pop_front() {
+            self.0.space.notify_waiters();
+            return Poll::Ready(Some(item));
+        }
+        if state.finished {
+            return Poll::Ready(None);
+        }
+        state.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl<T> Drop for QueueStream<T> {
+    fn drop(&mut self) {
+        self.0.closed.store(true, Ordering::Release);
+        self.0.space.notify_waiters();
+    }
+}
+
+#[derive(Default)]
+struct AsyncShared {
+    b2r_cache: Mutex<HashMap<u32, ProbeQueue>>,
+    b2r_notify: Notify,
+    // notify the server waiting for a full queue
+    b2r_space: Notify,
+    capacities: Mutex<HashMap<u32, Capacity>>,
+    default_capacity: Mutex<Option<Capacity>>,
+    dropped: Mutex<HashMap<u32, u64>>,
+    cycle: AtomicU32,
+    r2b_cache: Mutex<HashMap<u32, VecDeque<Vec<u8>>>>,
+    r2b_notify: Notify,
+    probe_streams: Mutex<Vec<(u32, Arc<StreamQueue<B2RMessage>>)>>,
+    cycle_streams: Mutex<Vec<Arc<StreamQueue<Vec<B2RMessage>>>>>,
+    finished: AtomicBool,
+    stop: AtomicBool,
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().expect("Fail to lock async server state")
+}
+
+impl AsyncShared {
+    fn pop_b2r(&self, id: u32) -> Option<B2RMessage> {
+        let message = lock(&self.b2r_cache)
+            .get_mut(&id)
+            .and_then(|queue| queue.pop_front());
+        if message.is_some() {
+            self.b2r_space.notify_waiters();
+        }
+        message
+    }
+
+    fn capacity(&self, id: u32) -> Option<Capacity> {
+        lock(&self.capacities)
+            .get(&id)
+            .copied()
+            .or(*lock(&self.default_capacity))
+    }
+
+    fn pop_r2b(&self, id: u32) -> Option<Vec<u8>> {
+        lock(&self.r2b_cache)
+            .get_mut(&id)
+            .and_then(|queue| queue.pop_front())
+    }
+
+    async fn next_r2b(&self, id: u32) -> Vec<u8> {
+        loop {
+            let notified = self.r2b_notify.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+            if let Some(message) = self.pop_r2b(id) {
+                return message;
+            }
+            notified.await;
+        }
+    }
+
+    fn add_dropped(&self, id: u32) {
+        *lock(&self.dropped).entry(id).or_default() += 1;
+    }
+
+    /// Push the message to the streams of its probe and the cycle streams,
+    /// or to the queue of its probe for get() if there are no such streams.
+    /// Wait for the stream or get() while the queue is full if the policy is Block.
+    async fn receive(&self, b2r_message: B2RMessage, pending: &mut BTreeMap<u32, Vec<B2RMessage>>) {
+        let id = b2r_message.id;
+        let probe_streams: Vec<Arc<StreamQueue<B2RMessage>>> = {
+            let mut probe_streams = lock(&self.probe_streams);
+            probe_streams.retain(|(_, queue)| !queue.closed.load(Ordering::Acquire));
+            probe_streams
+                .iter()
+                .filter(|(stream_id, _)| *stream_id == id)
+                .map(|(_, queue)| queue.clone())
+                .collect()
+        };
+        let cycle_streamed = {
+            let mut cycle_streams = lock(&self.cycle_streams);
+            cycle_streams.retain(|queue| !queue.closed.load(Ordering::Acquire));
+            !cycle_streams.is_empty()
+        };
+        for queue in &probe_streams {
+            if !queue.push(b2r_message.clone()).await {
+                self.add_dropped(id);
+            }
+        }
+        if cycle_streamed {
+            pending
+                .entry(b2r_message.cycles)
+                .or_default()
+                .push(b2r_message);
+            return;
+        }
+        if !probe_streams.is_empty() {
+            return;
+        }
+        let capacity = self.capacity(id);
+        let mut message = b2r_message;
+        loop {
+            let space = self.b2r_space.notified();
+            tokio::pin!(space);
+            space.as_mut().enable();
+            let push = lock(&self.b2r_cache)
+                .entry(id)
+                .or_insert_with(|| ProbeQueue::new(id, None))
+                .push_back(message, None, capacity);
+            match push {
+                Push::Pushed => break,
+                Push::Dropped => {
+                    self.add_dropped(id);
+                    break;
+                }
+                Push::Full(rejected) => {
+                    message = rejected;
+                    space.await;
+                }
+            }
+        }
+        self.b2r_notify.notify_waiters();
+    }
+
+    /// Push the pending cycles no later than cycle to the cycle streams.
+    async fn flush_cycles(&self, pending: &mut BTreeMap<u32, Vec<B2RMessage>>, cycle: Option<u32>) {
+        let later = match cycle {
+            Some(cycle) => pending.split_off(&cycle.saturating_add(1)),
+            None => BTreeMap::new(),
+        };
+        let complete = std::mem::replace(pending, later);
+        let cycle_streams = lock(&self.cycle_streams).clone();
+        for messages in complete.into_values() {
+            for queue in &cycle_streams {
+                if !queue.push(messages.clone()).await {
+                    messages
+                        .iter()
+                        .for_each(|message| self.add_dropped(message.id));
+                }
+            }
+        }
+    }
+
+    fn finish(&self) {
+        self.finished.store(true, Ordering::Release);
+        // end the streams
+        for (_, queue) in lock(&self.probe_streams).drain(..) {
+            queue.finish();
+        }
+        for queue in lock(&self.cycle_streams).drain(..) {
+            queue.finish();
+        }
+        self.b2r_notify.notify_waiters();
+    }
+}
+
+/// Finish the server when the serving task returns or panics.
+struct FinishOnDrop(Arc<AsyncShared>);
+
+impl Drop for FinishOnDrop {
+    fn drop(&mut self) {
+        self.0.finish();
+    }
+}
+
+/// An async server for interacting with Bluesim on the tokio runtime, enabled by the "async" feature.
+/// It can be cloned and shared by the tasks.
+#[derive(Clone)]
+pub struct AsyncB2RServer {
+    socket_path: String,
+    shared: Arc<AsyncShared>,
+}
+
+impl AsyncB2RServer {
+    /// make a new server with a socket path
+    /// you need to specify the B2R_SOCKET environment variable when start the bluesim
+    pub fn new_with(path: &str) -> Self {
+        AsyncB2RServer {
+            socket_path: path.to_string(),
+            shared: Arc::new(AsyncShared::default()),
+        }
+    }
+
+    /// Create a UnixListener at socket_path and spawn a task to serve Bluesim.
+    /// Must be called in a tokio runtime before running your Bluesim program.
+    /// The task returns when bluesim called shut_down_server().
+    pub fn serve(&self) -> io::Result<JoinHandle<io::Result<()>>> {
+        let _ = fs::remove_file(self.socket_path.as_str());
+        let listener = UnixListener::bind(self.socket_path.as_str())?;
+        let shared = self.shared.clone();
+        Ok(tokio::spawn(async move {
+            let _finish = FinishOnDrop(shared.clone());
+            let (mut stream, _) = listener.accept().await?;
+            // the messages of the incomplete cycles for the cycle streams
+            let mut pending: BTreeMap<u32, Vec<B2RMessage>> = BTreeMap::new();
+            loop {
+                match receive_getput(&mut stream).await? {
+                    GetPutMessage::Get(id, cycles) => {
+                        update_cycle(&shared.cycle, cycles);
+                        let message = shared.next_r2b(id).await;
+                        stream.write_all(&message).await?;
+                    }
+                    GetPutMessage::TryGet(id, cycles) => {
+                        update_cycle(&shared.cycle, cycles);
+                        let response = match shared.pop_r2b(id) {
+                            Some(message) => [vec![1], message].concat(),
+                            None => vec![0],
+                        };
+                        stream.write_all(&response).await?;
+                    }
+                    GetPutMessage::Put(b2r_message) => {
+                        update_cycle(&shared.cycle, b2r_message.cycles);
+                        shared.receive(b2r_message, &mut pending).await;
+                    }
+                    GetPutMessage::CycleEnd(cycles) => {
+                        update_cycle(&shared.cycle, cycles);
+                        shared.flush_cycles(&mut pending, Some(cycles)).await;
+                        let answer = match shared.stop.load(Ordering::Acquire) {
+                            true => CYCLE_END_STOP,
+                            false => CYCLE_END_ACK,
+                        };
+                        stream.write_all(&[answer]).await?;
+                    }
+                    GetPutMessage::ShutDown => {
+                        shared.flush_cycles(&mut pending, None).await;
+                        return Ok(());
+                    }
+                }
+            }
+        }))
+    }
+
+    /// Return the earliest message from the probe with id, wait until there is one.
+    /// Return None if Bluesim has shut down the server and there are no more messages.
+    pub async fn get(&self, id: u32) -> Option<B2RMessage> {
+        loop {
+            let notified = self.shared.b2r_notify.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+            if let Some(message) = self.shared.pop_b2r(id) {
+                return Some(message);
+            }
+            if self.shared.finished.load(Ordering::Acquire) {
+                return None;
+            }
+            notified.await;
+        }
+    }
+
+    /// Return the earliest message from the probe with id.
+    /// Return None if there is no message available for retrieval.
+    pub fn try_get(&self, id: u32) -> Option<B2RMessage> {
+        self.shared.pop_b2r(id)
+    }
+
+    /// Send a message to the probe with ID "id".
+    /// Please ensure that message.len() == ceil(get_t_width/8),
+    /// where get_t_width is the width of get_t defined in your BSV code.
+    pub async fn put(&self, id: u32, message: Vec<u8>) {
+        lock(&self.shared.r2b_cache)
+            .entry(id)
+            .or_default()
+            .push_back(message);
+        self.shared.r2b_notify.notify_waiters();
+    }
+
+    /// Return a stream of the messages from the probe with id received after the call.
+    /// While the stream is alive, the messages go to the stream instead of get().
+    /// The stream is bounded by the capacity of the probe, 1024 messages with Block by default,
+    /// SpillToDisk blocks like Block.
+    /// The stream ends when Bluesim shuts down the server.
+    pub fn probe_stream(&self, id: u32) -> impl Stream<Item = B2RMessage> {
+        let queue = Arc::new(StreamQueue::new(
+            self.shared.capacity(id).unwrap_or(DEFAULT_STREAM_CAPACITY),
+        ));
+        match self.shared.finished.load(Ordering::Acquire) {
+            true => queue.finish(),
+            false => lock(&self.shared.probe_streams).push((id, queue.clone())),
+        }
+        QueueStream(queue)
+    }
+
+    /// Return a stream of all the messages of every complete cycle received after the call.
+    /// While the stream is alive, the messages go to the stream instead of get().
+    /// The stream is bounded by the default capacity in cycles, 1024 cycles with Block by default,
+    /// SpillToDisk blocks like Block.
+    /// The stream ends when Bluesim shuts down the server.
+    pub fn cycle_stream(&self) -> impl Stream<Item = Vec<B2RMessage>> {
+        let capacity = *lock(&self.shared.default_capacity);
+        let queue = Arc::new(StreamQueue::new(
+            capacity.unwrap_or(DEFAULT_STREAM_CAPACITY),
+        ));
+        match self.shared.finished.load(Ordering::Acquire) {
+            true => queue.finish(),
+            false => lock(&self.shared.cycle_streams).push(queue.clone()),
+        }
+        QueueStream(queue)
+    }
+
+    /// Bound the queue and the streams of the probe with id created after the call,
+    /// the queues read by get() grow without limit by default.
+    pub fn set_capacity(&self, id: u32, capacity: usize, policy: OverflowPolicy) {
+        assert!(capacity > 0, "capacity must be positive");
+        lock(&self.shared.capacities).insert(id, Capacity { capacity, policy });
+    }
+
+    /// Bound the queues and the streams of the probes without their own capacity
+    /// set by AsyncB2RServer::set_capacity(), and the cycle streams created after the call.
+    pub fn set_default_capacity(&self, capacity: usize, policy: OverflowPolicy) {
+        assert!(capacity > 0, "capacity must be positive");
+        *lock(&self.shared.default_capacity) = Some(Capacity { capacity, policy });
+    }
+
+    /// return the number of messages of the probe with id dropped by the full queue
+    pub fn dropped_messages(&self, id: u32) -> u64 {
+        lock(&self.shared.dropped)
+            .get(&id)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// return the newest cycle reported by Bluesim
+    pub fn current_cycle(&self) -> u32 {
+        self.shared.cycle.load(Ordering::Acquire)
+    }
+
+    /// Request Bluesim to stop at the end of the current cycle.
+    pub fn request_stop(&self) {
+        self.shared.stop.store(true, Ordering::Release);
+    }
+
+    /// return true if Bluesim has shut down the server
+    pub fn finished(&self) -> bool {
+        self.shared.finished.load(Ordering::Acquire)
+    }
+}
+
+async fn receive_getput(stream: &mut UnixStream) -> io::Result<GetPutMessage> {
+    // the first MSG_SIZE_BYTES bytes is the size of message
+    let mut sz_buf = [0; MSG_SIZE_BYTES];
+    stream.read_exact(&mut sz_buf).await?;
+    let sz_msg = MsgSizeType::from_le_bytes(sz_buf) as usize;
+    let mut buffer = vec![0; sz_msg];
+    stream.read_exact(&mut buffer).await?;
+    bincode::deserialize::<GetPutMessage>(&buffer)
+        .map_err(|err| Error::new(ErrorKind::InvalidData, err))
+}
@@ -1,2 +1,37 @@
 pub type MsgSizeType = u32;
 pub const MSG_SIZE_BYTES: usize = std::mem::size_of::<MsgSizeType>();
+/// The answer of a CycleEnd message, Bluesim continues to the next cycle.
+pub const CYCLE_END_ACK: u8 = 0;
+/// The answer of a CycleEnd message, Rust requests Bluesim to stop.
+pub const CYCLE_END_STOP: u8 = 1;
+/// The size of a message of mkFIFOFProbe: notFull, notEmpty.
+pub const FIFO_INFO_BYTES: usize = 2;
+/// The size of a message of mkFIFOFOccupancyProbe:
+/// notFull, notEmpty, enq fired, deq fired, and the element count in 4 bytes.
+pub const FIFO_OCCUPANCY_BYTES: usize = 8;
+
+/// The description of a probe:
+/// - id: ID of the probe
+/// - name: a readable name of the probe
+/// - put_width: the width of put_t in bits
+/// - get_width: the width of get_t in bits
+#[derive(Clone, Debug)]
+pub struct ProbeInfo {
+    pub id: u32,
+    pub name: String,
+    pub put_width: u32,
+    pub get_width: u32,
+}
+
+/// What the server does when a getter's queue of a probe is full:
+/// - Block: wait for the getter, Bluesim blocks at the put (backpressure through the socket)
+/// - DropOldest: drop the earliest message in the queue
+/// - DropNewest: drop the new message
+/// - SpillToDisk: keep the new messages in a temporary file until the queue has room
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    Block,
+    DropOldest,
+    DropNewest,
+    SpillToDisk,
+}
@@ -5,10 +5,23 @@
 #[allow(clippy::unwrap_used)]
 mod test;
 
+#[cfg(feature = "async")]
+mod async_server;
 mod config;
+mod memory;
+mod pipeline;
+mod pool;
 mod publisher;
 mod server;
+mod store;
+mod verify;
 
+#[cfg(feature = "async")]
+pub use async_server::*;
 pub use config::*;
+pub use memory::*;
+pub use pipeline::*;
 pub use publisher::*;
 pub use server::*;
+pub use store::*;
+pub use verify::*;
@@ -0,0 +1,318 @@
+use crate::server::*;
+use std::collections::VecDeque;
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::sync::{Arc, Mutex};
+
+/// A memory model answering the requests of a processor design.
+///
+/// The request probe sends a `RMemReq#(addr_w, data_w)` (defined in RProbe.bsv) through put_data,
+/// the fields are packed as {write, addr, byte_en, data}.
+/// The response probe reads a `Maybe#(Bit#(data_w))` through get_data,
+/// a read request sent at cycle c becomes valid when the response probe gets data at cycle c + latency.
+/// Write requests have no response.
+/// An access out of the memory doesn't stop the simulation, it's recorded as a BadAccess,
+/// a bad read is answered with the error response and a bad write is ignored.
+pub struct MemoryModel {
+    memory: Vec<u8>,
+    base: u64,
+    addr_width: usize,
+    data_bytes: usize,
+    latency: u32,
+    // (ready cycle, data) of the read requests
+    responses: VecDeque<(u32, Vec<u8>)>,
+    error_response: Vec<u8>,
+    bad_accesses: Vec<BadAccess>,
+}
+
+/// An access out of the memory requested by the DUT:
+/// - cycle: the cycle of the request
+/// - addr: the aligned address of the request
+/// - write: true for a write request
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BadAccess {
+    pub cycle: u32,
+    pub addr: u64,
+    pub write: bool,
+}
+
+impl MemoryModel {
+    /// Make a memory with size bytes, every request accesses data_bytes bytes.
+    /// The address width is 32 bits, the base address is 0 and the latency is 1 cycle by default.
+    pub fn new(size: usize, data_bytes: usize) -> Self {
+        assert!(data_bytes > 0, "data_bytes must be positive");
+        MemoryModel {
+            memory: vec![0; size],
+            base: 0,
+            addr_width: 32,
+            data_bytes,
+            latency: 1,
+            responses: VecDeque::new(),
+            error_response: vec![0; data_bytes],
+            bad_accesses: Vec::new(),
+        }
+    }
+
+    /// Set the address of the first byte of the memory.
+    pub fn set_base(&mut self, base: u64) {
+        self.base = base;
+    }
+
+    /// Set the width of the addr field in the request, must be no more than 64.
+    pub fn set_addr_width(&mut self, addr_width: usize) {
+        assert!(addr_width <= 64, "addr_width must be no more than 64");
+        self.addr_width = addr_width;
+    }
+
+    /// Set the cycles between a read request and its response.
+    pub fn set_latency(&mut self, latency: u32) {
+        self.latency = latency;
+    }
+
+    /// Set the data answered to a read out of the memory, zeros by default.
+    pub fn set_error_response(&mut self, data: &[u8]) {
+        assert_eq!(
+            data.len(),
+            self.data_bytes,
+            "the error response must have data_bytes bytes"
+        );
+        self.error_response = data.to_vec();
+    }
+
+    /// return the accesses out of the memory requested by the DUT
+    pub fn bad_accesses(&self) -> &[BadAccess] {
+        &self.bad_accesses
+    }
+
+    /// Read len bytes from addr.
+    pub fn read(&self, addr: u64, len: usize) -> &[u8] {
+        let offset = self.offset(addr, len);
+        &self.memory[offset..offset + len]
+    }
+
+    /// Write data to addr.
+    pub fn write(&mut self, addr: u64, data: &[u8]) {
+        let offset = self.offset(addr, data.len());
+        self.memory[offset..offset + data.len()].copy_from_slice(data);
+    }
+
+    /// Load a hex file in the $readmemh format,
+    /// every word has data_bytes bytes and "@addr" sets the word address.
+    /// Return an InvalidData error if a word is out of the memory.
+    pub fn load_hex(&mut self, path: &str) -> std::io::Result<()> {
+        let content = fs::read_to_string(path)?;
+        // None after the last word address
+        let mut word_addr = Some(0u64);
+        for line in content.lines() {
+            let line = line.split("//").next().unwrap_or_default();
+            for token in line.split_whitespace() {
+                if let Some(addr) = token.strip_prefix('@') {
+                    word_addr = Some(
+                        u64::from_str_radix(addr, 16)
+                            .map_err(|err| Error::new(ErrorKind::InvalidData, err))?,
+                    );
+                    continue;
+                }
+                let word = parse_hex_word(token, self.data_bytes)?;
+                let addr = word_addr
+                    .and_then(|word_addr| word_addr.checked_mul(self.data_bytes as u64))
+                    .and_then(|offset| offset.checked_add(self.base));
+                self.load(addr, &word)?;
+                word_addr = word_addr.and_then(|word_addr| word_addr.checked_add(1));
+            }
+        }
+        Ok(())
+    }
+
+    /// Load the PT_LOAD segments of a little endian ELF file at their physical addresses.
+    /// Return an InvalidData error if the file is malformed or a segment is out of the memory.
+    pub fn load_elf(&mut self, path: &str) -> std::io::Result<()> {
+        let elf = fs::read(path)?;
+        let invalid = |msg: &str| Error::new(ErrorKind::InvalidData, msg.to_string());
+        if elf.len() < 0x34 || elf[0..4] != [0x7f, b'E', b'L', b'F'] {
+            return Err(invalid("not an ELF file"));
+        }
+        if elf[5] != 1 {
+            return Err(invalid("only little endian ELF is supported"));
+        }
+        let is_64 = match elf[4] {
+            1 => false,
+            2 => true,
+            _ => return Err(invalid("unknown ELF class")),
+        };
+        let field = |offset: u64, size: usize| -> std::io::Result<u64> {
+            usize::try_from(offset)
+                .ok()
+                .and_then(|start| elf.get(start..start.checked_add(size)?))
+                .map(le_to_u64)
+                .ok_or_else(|| invalid("truncated ELF file"))
+        };
+
+        let (phoff, phentsize, phnum) = if is_64 {
+            (field(0x20, 8)?, field(0x36, 2)?, field(0x38, 2)?)
+        } else {
+            (field(0x1c, 4)?, field(0x2a, 2)?, field(0x2c, 2)?)
+        };
+        for i in 0..phnum {
+            let ph = i
+                .checked_mul(phentsize)
+                .and_then(|offset| offset.checked_add(phoff))
+                .ok_or_else(|| invalid("truncated ELF file"))?;
+            let ph_field = |offset: u64, size: usize| match ph.checked_add(offset) {
+                Some(offset) => field(offset, size),
+                None => Err(invalid("truncated ELF file")),
+            };
+            // PT_LOAD
+            if ph_field(0, 4)? != 1 {
+                continue;
+            }
+            let (offset, paddr, filesz) = if is_64 {
+                (ph_field(0x08, 8)?, ph_field(0x18, 8)?, ph_field(0x20, 8)?)
+            } else {
+                (ph_field(0x04, 4)?, ph_field(0x0c, 4)?, ph_field(0x10, 4)?)
+            };
+            let data = offset
+                .checked_add(filesz)
+                .and_then(|end| Some((usize::try_from(offset).ok()?, usize::try_from(end).ok()?)))
+                .and_then(|(start, end)| elf.get(start..end))
+                .ok_or_else(|| invalid("truncated ELF segment"))?;
+            self.load(Some(paddr), data)?;
+        }
+        Ok(())
+    }
+
+    /// Write the loaded data to addr, None if the address overflows.
+    fn load(&mut self, addr: Option<u64>, data: &[u8]) -> std::io::Result<()> {
+        match addr.filter(|addr| self.checked_offset(*addr, data.len()).is_some()) {
+            Some(addr) => {
+                self.write(addr, data);
+                Ok(())
+            }
+            None => Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("the loaded data is out of the memory: {:#x?}", addr),
+            )),
+        }
+    }
+
+    /// Answer the requests from the probe with req_id through the probe with resp_id.
+    /// Return the shared memory, which can be inspected while or after the simulation.
+    /// The requests are processed when the response probe gets data.
+    pub fn attach(self, server: &mut B2RServer, req_id: u32, resp_id: u32) -> Arc<Mutex<Self>> {
+        let model = Arc::new(Mutex::new(self));
+        let handler_model = model.clone();
+        let mut id_getter = IDGetter::with_ids(server, &[req_id]);
+        server.set_handler(resp_id, move |context: GetContext, _: &[B2RMessage]| {
+            let mut model = handler_model.lock().expect("Fail to lock memory model");
+            for request in id_getter.get_id_all(req_id) {
+                model.request(&request);
+            }
+            model.response(context.cycles)
+        });
+        model
+    }
+
+    /// Process a request message sent by the request probe.
+    fn request(&mut self, request: &B2RMessage) {
+        let data_bytes = self.data_bytes;
+        let byte_en_lo = data_bytes * 8;
+        let addr_lo = byte_en_lo + data_bytes;
+        let write_lo = addr_lo + self.addr_width;
+        assert_eq!(
+            request.message.len(),
+            (write_lo + 1).div_ceil(8),
+            "the request size doesn't match the memory model"
+        );
+
+        let byte_en = get_bits(&request.message, byte_en_lo, data_bytes);
+        let addr = get_bits(&request.message, addr_lo, self.addr_width);
+        let aligned = addr - addr % data_bytes as u64;
+        let write = get_bits(&request.message, write_lo, 1) == 1;
+        if self.checked_offset(aligned, data_bytes).is_none() {
+            self.bad_accesses.push(BadAccess {
+                cycle: request.cycles,
+                addr: aligned,
+                write,
+            });
+            if !write {
+                let ready = request.cycles.saturating_add(self.latency);
+                self.responses
+                    .push_back((ready, self.error_response.clone()));
+            }
+            return;
+        }
+        if write {
+            for (lane, byte) in request.message[..data_bytes].iter().enumerate() {
+                if byte_en >> lane & 1 == 1 {
+                    self.write(aligned + lane as u64, &[*byte]);
+                }
+            }
+        } else {
+            let data = self.read(aligned, data_bytes).to_vec();
+            let ready = request.cycles.saturating_add(self.latency);
+            self.responses.push_back((ready, data));
+        }
+    }
+
+    /// The packed Maybe#(Bit#(data_w)) response at cycles.
+    fn response(&mut self, cycles: u32) -> Vec<u8> {
+        let mut response = vec![0; self.data_bytes + 1];
+        if self
+            .responses
+            .front()
+            .is_some_and(|(ready, _)| *ready <= cycles)
+        {
+            let (_, data) = self.responses.pop_front().expect("front error");
+            response[..self.data_bytes].copy_from_slice(&data);
+            response[self.data_bytes] = 1;
+        }
+        response
+    }
+
+    fn offset(&self, addr: u64, len: usize) -> usize {
+        self.checked_offset(addr, len)
+            .unwrap_or_else(|| panic!("memory access out of range: {:#x}", addr))
+    }
+
+    fn checked_offset(&self, addr: u64, len: usize) -> Option<usize> {
+        let offset = addr.checked_sub(self.base)?;
+        (offset.checked_add(len as u64)? <= self.memory.len() as u64).then_some(offset as usize)
+    }
+}
+
+/// Get width (no more than 64) bits from lo of a little endian bit vector.
+fn get_bits(bytes: &[u8], lo: usize, width: usize) -> u64 {
+    (0..width).fold(0, |value, i| {
+        let bit = (bytes[(lo + i) / 8] >> ((lo + i) % 8)) & 1;
+        value | (bit as u64) << i
+    })
+}
+
+fn le_to_u64(bytes: &[u8]) -> u64 {
+    bytes
+        .iter()
+        .rev()
+        .fold(0, |value, byte| value << 8 | *byte as u64)
+}
+
+/// Parse a big endian hex word into little endian bytes.
+fn parse_hex_word(token: &str, data_bytes: usize) -> std::io::Result<Vec<u8>> {
+    let digits: Vec<char> = token.chars().filter(|c| *c != '_').collect();
+    let mut word = vec![0; data_bytes];
+    for (i, pair) in digits.rchunks(2).enumerate() {
+        let byte: String = pair.iter().collect();
+        let byte =
+            u8::from_str_radix(&byte, 16).map_err(|err| Error::new(ErrorKind::InvalidData, err))?;
+        match word.get_mut(i) {
+            Some(slot) => *slot = byte,
+            None if byte == 0 => {}
+            None => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("hex word {} is wider than {} bytes", token, data_bytes),
+                ))
+            }
+        }
+    }
+    Ok(word)
+}
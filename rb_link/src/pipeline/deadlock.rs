@@ -0,0 +1,214 @@
+use super::{PipelineTopology, StageStatus};
+use crate::server::{FifoStatus, PipeLineState};
+use std::collections::HashSet;
+use std::fmt;
+
+/// Why a rule didn't fire in a cycle:
+/// - Starved: the input fifo is empty
+/// - Blocked: the output fifo is full
+/// - Stuck: the inputs are not empty and the outputs are not full, the rule is stuck by itself
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RuleStatus {
+    Starved(u32),
+    Blocked(u32),
+    Stuck,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RuleDiagnosis {
+    pub rule: u32,
+    pub status: RuleStatus,
+}
+
+/// The report of a deadlock:
+/// - since: the first cycle with no rule firing
+/// - cycle: the cycle the deadlock is detected
+/// - full_fifos, empty_fifos, missing_fifos: the fifo states at cycle
+/// - rules: the diagnoses of the rules of the stages at cycle
+/// - chain: the rules waiting for each other, from a blocked rule to the likely blocking rule
+/// - blocking_rule: the likely blocking rule, the last rule of chain
+#[derive(Clone, Debug)]
+pub struct DeadlockReport {
+    pub since: u32,
+    pub cycle: u32,
+    pub full_fifos: Vec<u32>,
+    pub empty_fifos: Vec<u32>,
+    pub missing_fifos: Vec<u32>,
+    pub rules: Vec<RuleDiagnosis>,
+    pub chain: Vec<RuleDiagnosis>,
+    pub blocking_rule: Option<u32>,
+}
+
+impl fmt::Display for DeadlockReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "pipeline stuck since cycle {} (detected at cycle {})",
+            self.since, self.cycle
+        )?;
+        writeln!(f, "full fifos: {:?}", self.full_fifos)?;
+        writeln!(f, "empty fifos: {:?}", self.empty_fifos)?;
+        if !self.missing_fifos.is_empty() {
+            writeln!(f, "missing fifos: {:?}", self.missing_fifos)?;
+        }
+        for diagnosis in &self.chain {
+            match diagnosis.status {
+                RuleStatus::Starved(fifo) => {
+                    writeln!(f, "rule {} waits for empty fifo {}", diagnosis.rule, fifo)?
+                }
+                RuleStatus::Blocked(fifo) => {
+                    writeln!(f, "rule {} waits for full fifo {}", diagnosis.rule, fifo)?
+                }
+                RuleStatus::Stuck => writeln!(
+                    f,
+                    "rule {} cannot fire: its inputs are not empty and its outputs are not full",
+                    diagnosis.rule
+                )?,
+            }
+        }
+        if let Some(rule) = self.blocking_rule {
+            writeln!(f, "likely blocking rule: {}", rule)?;
+        }
+        Ok(())
+    }
+}
+
+type DeadlockCallback = Box<dyn FnMut(&DeadlockReport) + Send>;
+
+/// Detect the cycles a pipeline makes no progress from the PipeLineStates.
+/// Reports a deadlock after threshold consecutive cycles with no rule firing.
+/// Declare which fifos every rule reads and writes by a PipelineTopology or connect()
+/// to find the likely blocking rule.
+pub struct DeadlockDetector {
+    threshold: u32,
+    topology: PipelineTopology,
+    idle_cycles: u32,
+    idle_since: Option<u32>,
+    report: Option<DeadlockReport>,
+    callback: Option<DeadlockCallback>,
+}
+
+impl DeadlockDetector {
+    pub fn new(threshold: u32) -> Self {
+        DeadlockDetector::with_topology(threshold, PipelineTopology::new())
+    }
+
+    pub fn with_topology(threshold: u32, topology: PipelineTopology) -> Self {
+        assert!(threshold > 0, "threshold must be positive");
+        DeadlockDetector {
+            threshold,
+            topology,
+            idle_cycles: 0,
+            idle_since: None,
+            report: None,
+            callback: None,
+        }
+    }
+
+    /// Declare the rule probe with id rule reads the fifos with ids inputs and writes the fifos with ids outputs.
+    /// Connect the rules from the pipeline input to the output.
+    /// The same as adding a stage named "rule {rule}" to the topology.
+    pub fn connect(&mut self, rule: u32, inputs: &[u32], outputs: &[u32]) -> &mut Self {
+        self.topology
+            .add_stage(&format!("rule {}", rule), rule, inputs, outputs);
+        self
+    }
+
+    /// Call callback with the report when a deadlock is detected.
+    pub fn on_deadlock(&mut self, callback: impl FnMut(&DeadlockReport) + Send + 'static) {
+        self.callback = Some(Box::new(callback));
+    }
+
+    /// Feed the state of the next cycle, the states without a cycle are ignored.
+    /// Return the report when a deadlock is detected, once for every deadlock.
+    pub fn update(&mut self, state: &PipeLineState) -> Option<DeadlockReport> {
+        let cycle = state.cycle?;
+        if !state.fire_rules.is_empty() {
+            self.idle_cycles = 0;
+            self.idle_since = None;
+            return None;
+        }
+        let since = *self.idle_since.get_or_insert(cycle);
+        self.idle_cycles += 1;
+        if self.idle_cycles != self.threshold {
+            return None;
+        }
+
+        let rules: Vec<RuleDiagnosis> = self
+            .topology
+            .stages()
+            .iter()
+            .map(|stage| RuleDiagnosis {
+                rule: stage.rule,
+                status: match self.topology.status(stage, state) {
+                    StageStatus::Starved(fifo) => RuleStatus::Starved(fifo),
+                    StageStatus::Blocked(fifo) => RuleStatus::Blocked(fifo),
+                    StageStatus::Fired | StageStatus::Idle => RuleStatus::Stuck,
+                },
+            })
+            .collect();
+        let chain = self.chain(&rules);
+        let report = DeadlockReport {
+            since,
+            cycle,
+            full_fifos: state.full_fifos(),
+            empty_fifos: state.empty_fifos(),
+            missing_fifos: state.fifos_with(FifoStatus::Missing),
+            blocking_rule: chain.last().map(|diagnosis| diagnosis.rule),
+            rules,
+            chain,
+        };
+        if let Some(callback) = &mut self.callback {
+            callback(&report);
+        }
+        self.report = Some(report.clone());
+        Some(report)
+    }
+
+    /// return the report of the latest deadlock
+    pub fn report(&self) -> Option<&DeadlockReport> {
+        self.report.as_ref()
+    }
+
+    /// return true if the pipeline is deadlocked in the latest cycle
+    pub fn deadlocked(&self) -> bool {
+        self.idle_cycles >= self.threshold
+    }
+
+    /// Follow the full fifos downstream from the first blocked rule,
+    /// or the empty fifos upstream from the last starved rule, until a stuck rule.
+    fn chain(&self, rules: &[RuleDiagnosis]) -> Vec<RuleDiagnosis> {
+        let start = rules
+            .iter()
+            .find(|diagnosis| matches!(diagnosis.status, RuleStatus::Blocked(_)))
+            .or_else(|| {
+                rules
+                    .iter()
+                    .rev()
+                    .find(|diagnosis| matches!(diagnosis.status, RuleStatus::Starved(_)))
+            })
+            .or_else(|| {
+                rules
+                    .iter()
+                    .find(|diagnosis| diagnosis.status == RuleStatus::Stuck)
+            });
+        let mut chain: Vec<RuleDiagnosis> = Vec::new();
+        let mut visited: HashSet<u32> = HashSet::new();
+        let mut next = start;
+        while let Some(diagnosis) = next {
+            // a circular wait
+            if !visited.insert(diagnosis.rule) {
+                break;
+            }
+            chain.push(diagnosis.clone());
+            let stage = match diagnosis.status {
+                RuleStatus::Blocked(fifo) => self.topology.reader(fifo),
+                RuleStatus::Starved(fifo) => self.topology.writer(fifo),
+                RuleStatus::Stuck => None,
+            };
+            let rule = stage.map(|stage| stage.rule);
+            next = rule.and_then(|rule| rules.iter().find(|diagnosis| diagnosis.rule == rule));
+        }
+        chain
+    }
+}
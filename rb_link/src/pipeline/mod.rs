@@ -0,0 +1,6 @@
+mod deadlock;
+mod stats;
+mod topology;
+pub use deadlock::*;
+pub use stats::*;
+pub use topology::*;
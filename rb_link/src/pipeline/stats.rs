@@ -0,0 +1,360 @@
+use super::PipelineTopology;
+use crate::publisher::{Subscriber, Summary};
+use crate::server::{B2RMessage, FifoOccupancy, FifoStatus, PipeLineState, R2BMessage};
+use serde::Serialize;
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+use std::path::PathBuf;
+
+/// The statistics of a rule probe:
+/// - fired: the cycles the rule fired
+/// - fire_rate: fired / the recorded cycles
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct RuleStats {
+    pub id: u32,
+    pub fired: u64,
+    pub fire_rate: f64,
+}
+
+/// The statistics of a fifo probe:
+/// - full_cycles, empty_cycles: the cycles the fifo is full or empty
+/// - missing_cycles: the cycles the fifo didn't report
+/// - full_fraction, empty_fraction: the fractions of the recorded cycles the fifo is full or empty
+/// - full_runs, empty_runs: histograms of the lengths of the consecutive full or empty cycles
+/// - longest_stall: the longest consecutive full or empty cycles
+///
+/// Only for a mkFIFOFOccupancyProbe:
+/// - enqs, deqs: the cycles the fifo is enqueued or dequeued
+/// - max_count, mean_count: the maximum and the mean element count
+/// - max_latency, mean_latency: the maximum and the mean cycles from the enq to the deq of an element
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct FifoStats {
+    pub id: u32,
+    pub full_cycles: u64,
+    pub empty_cycles: u64,
+    pub missing_cycles: u64,
+    pub full_fraction: f64,
+    pub empty_fraction: f64,
+    pub full_runs: BTreeMap<u64, u64>,
+    pub empty_runs: BTreeMap<u64, u64>,
+    pub longest_stall: u64,
+    pub enqs: u64,
+    pub deqs: u64,
+    pub max_count: u32,
+    pub mean_count: Option<f64>,
+    pub max_latency: u32,
+    pub mean_latency: Option<f64>,
+}
+
+impl FifoStats {
+    /// return true if the fifo is enqueued but never dequeued
+    pub fn never_drained(&self) -> bool {
+        self.enqs > 0 && self.deqs == 0
+    }
+}
+
+/// The summary of PipelineStats:
+/// - cycles: the number of the recorded cycles
+/// - first_cycle, last_cycle: the first and the last recorded cycles
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct StatsSummary {
+    pub cycles: u64,
+    pub first_cycle: Option<u32>,
+    pub last_cycle: Option<u32>,
+    pub rules: Vec<RuleStats>,
+    pub fifos: Vec<FifoStats>,
+}
+
+impl StatsSummary {
+    /// return the ids of the fifos enqueued but never dequeued
+    pub fn never_drained(&self) -> Vec<u32> {
+        self.fifos
+            .iter()
+            .filter(|fifo| fifo.never_drained())
+            .map(|fifo| fifo.id)
+            .collect()
+    }
+
+    /// return the summary in JSON
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("Fail to serialize summary")
+    }
+}
+
+/// A table of the rule fire rates and the fifo full/empty fractions.
+impl fmt::Display for StatsSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} cycles from {:?} to {:?}",
+            self.cycles, self.first_cycle, self.last_cycle
+        )?;
+        writeln!(f, "{:<8} {:>10} {:>10}", "rule", "fired", "fire rate")?;
+        for rule in &self.rules {
+            writeln!(
+                f,
+                "{:<8} {:>10} {:>9.1}%",
+                rule.id,
+                rule.fired,
+                rule.fire_rate * 100.0
+            )?;
+        }
+        writeln!(
+            f,
+            "{:<8} {:>10} {:>10} {:>14} {:>10} {:>12}",
+            "fifo", "full", "empty", "longest stall", "mean count", "mean latency"
+        )?;
+        let optional = |value: Option<f64>| match value {
+            Some(value) => format!("{:.2}", value),
+            None => "-".to_string(),
+        };
+        for fifo in &self.fifos {
+            writeln!(
+                f,
+                "{:<8} {:>9.1}% {:>9.1}% {:>14} {:>10} {:>12}",
+                fifo.id,
+                fifo.full_fraction * 100.0,
+                fifo.empty_fraction * 100.0,
+                fifo.longest_stall,
+                optional(fifo.mean_count),
+                optional(fifo.mean_latency)
+            )?;
+        }
+        for id in self.never_drained() {
+            writeln!(f, "fifo {} is never drained", id)?;
+        }
+        Ok(())
+    }
+}
+
+/// The current full and empty runs of a fifo,
+/// and the enq cycles of its elements, None for the elements before the first state.
+#[derive(Clone, Default)]
+struct FifoRuns {
+    stats: FifoStats,
+    full_run: u64,
+    empty_run: u64,
+    elements: Option<VecDeque<Option<u32>>>,
+    count_cycles: u64,
+    count_sum: u64,
+    latencies: u64,
+    latency_sum: u64,
+}
+
+impl FifoRuns {
+    fn record(&mut self, status: FifoStatus) {
+        if status == FifoStatus::Missing {
+            self.stats.missing_cycles += 1;
+        }
+        if status == FifoStatus::Full {
+            self.stats.full_cycles += 1;
+            self.full_run += 1;
+        } else {
+            end_run(&mut self.stats.full_runs, &mut self.full_run);
+        }
+        if status == FifoStatus::Empty {
+            self.stats.empty_cycles += 1;
+            self.empty_run += 1;
+        } else {
+            end_run(&mut self.stats.empty_runs, &mut self.empty_run);
+        }
+    }
+
+    fn record_occupancy(&mut self, cycle: u32, occupancy: &FifoOccupancy) {
+        let elements = self
+            .elements
+            .get_or_insert_with(|| vec![None; occupancy.count as usize].into());
+        self.count_cycles += 1;
+        self.count_sum += occupancy.count as u64;
+        self.stats.max_count = self.stats.max_count.max(occupancy.count);
+        let mut enq = occupancy.enq.then_some(cycle);
+        if occupancy.deq {
+            self.stats.deqs += 1;
+            // an element enqueued and dequeued in the same cycle bypasses the empty fifo
+            let enq_cycle = match elements.pop_front() {
+                Some(enq_cycle) => enq_cycle,
+                None => enq.take(),
+            };
+            if let Some(enq_cycle) = enq_cycle {
+                let latency = cycle.saturating_sub(enq_cycle);
+                self.latencies += 1;
+                self.latency_sum += latency as u64;
+                self.stats.max_latency = self.stats.max_latency.max(latency);
+            }
+        }
+        if occupancy.enq {
+            self.stats.enqs += 1;
+        }
+        if let Some(enq_cycle) = enq {
+            elements.push_back(Some(enq_cycle));
+        }
+    }
+
+    /// return the stats with the current runs ended
+    fn finish(&self, cycles: u64) -> FifoStats {
+        let mut runs = self.clone();
+        end_run(&mut runs.stats.full_runs, &mut runs.full_run);
+        end_run(&mut runs.stats.empty_runs, &mut runs.empty_run);
+        let mut stats = runs.stats;
+        stats.full_fraction = fraction(stats.full_cycles, cycles);
+        stats.empty_fraction = fraction(stats.empty_cycles, cycles);
+        stats.mean_count = mean(self.count_sum, self.count_cycles);
+        stats.mean_latency = mean(self.latency_sum, self.latencies);
+        stats.longest_stall = stats
+            .full_runs
+            .keys()
+            .chain(stats.empty_runs.keys())
+            .copied()
+            .max()
+            .unwrap_or_default();
+        stats
+    }
+}
+
+fn end_run(runs: &mut BTreeMap<u64, u64>, run: &mut u64) {
+    if *run > 0 {
+        *runs.entry(*run).or_default() += 1;
+        *run = 0;
+    }
+}
+
+fn mean(sum: u64, count: u64) -> Option<f64> {
+    (count > 0).then(|| sum as f64 / count as f64)
+}
+
+fn fraction(count: u64, cycles: u64) -> f64 {
+    match cycles {
+        0 => 0.0,
+        cycles => count as f64 / cycles as f64,
+    }
+}
+
+/// Collect the throughput, occupancy and utilization statistics of the fifo and rule probes,
+/// with the element counts and the queueing latencies of the fifos probed by mkFIFOFOccupancyProbe.
+/// The states come from PipeLineGetter, or from the subscribed messages as a Subscriber.
+pub struct PipelineStats {
+    rules: Vec<(u32, u64)>,
+    fifos: Vec<(u32, FifoRuns)>,
+    cycles: u64,
+    first_cycle: Option<u32>,
+    last_cycle: Option<u32>,
+    print_on_shutdown: bool,
+    json_path: Option<PathBuf>,
+}
+
+impl PipelineStats {
+    /// Collect the statistics of the fifo probes with ids fifos and the rule probes with ids rules.
+    pub fn new(fifos: &[u32], rules: &[u32]) -> Self {
+        PipelineStats {
+            rules: rules.iter().map(|id| (*id, 0)).collect(),
+            fifos: fifos
+                .iter()
+                .map(|id| {
+                    let mut runs = FifoRuns::default();
+                    runs.stats.id = *id;
+                    (*id, runs)
+                })
+                .collect(),
+            cycles: 0,
+            first_cycle: None,
+            last_cycle: None,
+            print_on_shutdown: false,
+            json_path: None,
+        }
+    }
+
+    /// Collect the statistics of all the fifos and rules of topology.
+    pub fn from_topology(topology: &PipelineTopology) -> Self {
+        let rules: Vec<u32> = topology.stages().iter().map(|stage| stage.rule).collect();
+        PipelineStats::new(&topology.fifos(), &rules)
+    }
+
+    /// Print the summary table on shutdown when used as a Subscriber.
+    pub fn set_print_on_shutdown(&mut self, print: bool) {
+        self.print_on_shutdown = print;
+    }
+
+    /// Write the JSON summary to path on shutdown when used as a Subscriber.
+    pub fn set_json_path(&mut self, path: impl Into<PathBuf>) {
+        self.json_path = Some(path.into());
+    }
+
+    /// Record the state of the next cycle, the states without a cycle are ignored.
+    pub fn update(&mut self, state: &PipeLineState) {
+        let Some(cycle) = state.cycle else {
+            return;
+        };
+        self.cycles += 1;
+        self.first_cycle.get_or_insert(cycle);
+        self.last_cycle = Some(cycle);
+        for (id, fired) in &mut self.rules {
+            if state.fired(*id) {
+                *fired += 1;
+            }
+        }
+        for (id, runs) in &mut self.fifos {
+            runs.record(state.status(*id));
+            if let Some(occupancy) = state.occupancy(*id) {
+                runs.record_occupancy(cycle, occupancy);
+            }
+        }
+    }
+
+    /// return the statistics so far
+    pub fn summary(&self) -> StatsSummary {
+        StatsSummary {
+            cycles: self.cycles,
+            first_cycle: self.first_cycle,
+            last_cycle: self.last_cycle,
+            rules: self
+                .rules
+                .iter()
+                .map(|(id, fired)| RuleStats {
+                    id: *id,
+                    fired: *fired,
+                    fire_rate: fraction(*fired, self.cycles),
+                })
+                .collect(),
+            fifos: self
+                .fifos
+                .iter()
+                .map(|(_, runs)| runs.finish(self.cycles))
+                .collect(),
+        }
+    }
+}
+
+impl Subscriber for PipelineStats {
+    fn update(&mut self, messages: Vec<B2RMessage>) -> Vec<R2BMessage> {
+        let fifos: Vec<u32> = self.fifos.iter().map(|(id, _)| *id).collect();
+        let rules: Vec<u32> = self.rules.iter().map(|(id, _)| *id).collect();
+        let state = PipeLineState::from_messages(&messages, &fifos, &rules);
+        PipelineStats::update(self, &state);
+        Vec::new()
+    }
+
+    fn subscribed_ids(&self) -> Vec<u32> {
+        self.fifos
+            .iter()
+            .map(|(id, _)| *id)
+            .chain(self.rules.iter().map(|(id, _)| *id))
+            .collect()
+    }
+
+    fn on_shutdown(&mut self, _summary: &Summary) {
+        let summary = self.summary();
+        if self.print_on_shutdown {
+            print!("{}", summary);
+        }
+        if let Some(path) = &self.json_path {
+            // report the error instead of panicking at the end of the run
+            if let Err(err) = std::fs::write(path, summary.to_json()) {
+                eprintln!(
+                    "Fail to write the JSON summary to {}: {}",
+                    path.display(),
+                    err
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,240 @@
+use crate::server::{FifoStatus, PipeLineGetter, PipeLineState};
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// A pipeline stage:
+/// - name: a readable name of the stage
+/// - rule: ID of the rule probe of the stage
+/// - inputs: IDs of the fifo probes the rule reads
+/// - outputs: IDs of the fifo probes the rule writes
+#[derive(Clone, Debug)]
+pub struct Stage {
+    pub name: String,
+    pub rule: u32,
+    pub inputs: Vec<u32>,
+    pub outputs: Vec<u32>,
+}
+
+/// What a stage did in a cycle:
+/// - Fired: the rule fired
+/// - Starved: the input fifo is empty
+/// - Blocked: the output fifo is full
+/// - Idle: the rule didn't fire for another reason
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StageStatus {
+    Fired,
+    Starved(u32),
+    Blocked(u32),
+    Idle,
+}
+
+/// The stages of a pipeline and the fifos connecting them.
+#[derive(Clone, Debug, Default)]
+pub struct PipelineTopology {
+    stages: Vec<Stage>,
+}
+
+impl PipelineTopology {
+    pub fn new() -> Self {
+        PipelineTopology::default()
+    }
+
+    /// Add a stage whose rule reads the fifos inputs and writes the fifos outputs.
+    /// Add the stages from the pipeline input to the output.
+    pub fn stage(mut self, name: &str, rule: u32, inputs: &[u32], outputs: &[u32]) -> Self {
+        self.add_stage(name, rule, inputs, outputs);
+        self
+    }
+
+    /// Add a stage like PipelineTopology::stage() without taking the topology.
+    pub fn add_stage(&mut self, name: &str, rule: u32, inputs: &[u32], outputs: &[u32]) {
+        self.stages.push(Stage {
+            name: name.to_string(),
+            rule,
+            inputs: inputs.to_vec(),
+            outputs: outputs.to_vec(),
+        });
+    }
+
+    /// return the stages in the order they are added
+    pub fn stages(&self) -> &[Stage] {
+        &self.stages
+    }
+
+    /// return the stage with the rule probe id
+    pub fn stage_of_rule(&self, rule: u32) -> Option<&Stage> {
+        self.stages.iter().find(|stage| stage.rule == rule)
+    }
+
+    /// return the stage reading the fifo
+    pub fn reader(&self, fifo: u32) -> Option<&Stage> {
+        self.stages
+            .iter()
+            .find(|stage| stage.inputs.contains(&fifo))
+    }
+
+    /// return the stage writing the fifo
+    pub fn writer(&self, fifo: u32) -> Option<&Stage> {
+        self.stages
+            .iter()
+            .find(|stage| stage.outputs.contains(&fifo))
+    }
+
+    /// return the IDs of all the fifos without duplicates
+    pub fn fifos(&self) -> Vec<u32> {
+        let mut fifos: Vec<u32> = Vec::new();
+        for stage in &self.stages {
+            for fifo in stage.inputs.iter().chain(&stage.outputs) {
+                if !fifos.contains(fifo) {
+                    fifos.push(*fifo);
+                }
+            }
+        }
+        fifos
+    }
+
+    /// Add all the fifo and rule probes of the topology to getter.
+    pub fn add_probes_to(&self, getter: &mut PipeLineGetter) {
+        for fifo in self.fifos() {
+            getter.add_fifo_probe(fifo);
+        }
+        for stage in &self.stages {
+            getter.add_rule_probe(stage.rule);
+        }
+    }
+
+    /// return what the stage did in the cycle of state
+    pub fn status(&self, stage: &Stage, state: &PipeLineState) -> StageStatus {
+        if state.fired(stage.rule) {
+            return StageStatus::Fired;
+        }
+        if let Some(fifo) = stage
+            .inputs
+            .iter()
+            .find(|fifo| state.status(**fifo) == FifoStatus::Empty)
+        {
+            return StageStatus::Starved(*fifo);
+        }
+        if let Some(fifo) = stage
+            .outputs
+            .iter()
+            .find(|fifo| state.status(**fifo) == FifoStatus::Full)
+        {
+            return StageStatus::Blocked(*fifo);
+        }
+        StageStatus::Idle
+    }
+}
+
+/// The cycles a stage spent on every status,
+/// starved_by and blocked_by count the cycles by the fifo responsible.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct StallHistogram {
+    pub fired: u64,
+    pub starved: u64,
+    pub blocked: u64,
+    pub idle: u64,
+    pub starved_by: BTreeMap<u32, u64>,
+    pub blocked_by: BTreeMap<u32, u64>,
+}
+
+impl StallHistogram {
+    fn record(&mut self, status: StageStatus) {
+        match status {
+            StageStatus::Fired => self.fired += 1,
+            StageStatus::Starved(fifo) => {
+                self.starved += 1;
+                *self.starved_by.entry(fifo).or_default() += 1;
+            }
+            StageStatus::Blocked(fifo) => {
+                self.blocked += 1;
+                *self.blocked_by.entry(fifo).or_default() += 1;
+            }
+            StageStatus::Idle => self.idle += 1,
+        }
+    }
+
+    /// return the number of the recorded cycles
+    pub fn cycles(&self) -> u64 {
+        self.fired + self.starved + self.blocked + self.idle
+    }
+}
+
+/// Attribute the stalls of every stage in every cycle and accumulate them into histograms,
+/// to tell where the throughput is lost.
+pub struct StallAttribution {
+    topology: PipelineTopology,
+    histograms: Vec<StallHistogram>,
+}
+
+impl StallAttribution {
+    pub fn new(topology: PipelineTopology) -> Self {
+        StallAttribution {
+            histograms: vec![StallHistogram::default(); topology.stages().len()],
+            topology,
+        }
+    }
+
+    /// Feed the state of the next cycle, return the status of every stage in the order of the stages.
+    /// The states without a cycle are ignored and return no status.
+    pub fn update(&mut self, state: &PipeLineState) -> Vec<StageStatus> {
+        if state.cycle.is_none() {
+            return Vec::new();
+        }
+        self.topology
+            .stages()
+            .iter()
+            .zip(&mut self.histograms)
+            .map(|(stage, histogram)| {
+                let status = self.topology.status(stage, state);
+                histogram.record(status);
+                status
+            })
+            .collect()
+    }
+
+    /// return the histogram of the stage with name
+    pub fn histogram(&self, name: &str) -> Option<&StallHistogram> {
+        self.topology
+            .stages()
+            .iter()
+            .position(|stage| stage.name == name)
+            .map(|index| &self.histograms[index])
+    }
+
+    /// return the stages with their histograms
+    pub fn histograms(&self) -> impl Iterator<Item = (&Stage, &StallHistogram)> {
+        self.topology.stages().iter().zip(&self.histograms)
+    }
+
+    pub fn topology(&self) -> &PipelineTopology {
+        &self.topology
+    }
+}
+
+/// A table of the percentage of the cycles every stage spent on every status.
+impl fmt::Display for StallAttribution {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{:<16} {:>8} {:>8} {:>8} {:>8}",
+            "stage", "fired", "starved", "blocked", "idle"
+        )?;
+        for (stage, histogram) in self.histograms() {
+            let percent = |count: u64| match histogram.cycles() {
+                0 => 0.0,
+                cycles => count as f64 * 100.0 / cycles as f64,
+            };
+            writeln!(
+                f,
+                "{:<16} {:>7.1}% {:>7.1}% {:>7.1}% {:>7.1}%",
+                stage.name,
+                percent(histogram.fired),
+                percent(histogram.starved),
+                percent(histogram.blocked),
+                percent(histogram.idle)
+            )?;
+        }
+        Ok(())
+    }
+}
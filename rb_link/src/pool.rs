@@ -0,0 +1,60 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// A fixed number of threads running the jobs in the order they are executed.
+pub(crate) struct ThreadPool {
+    sender: Option<Sender<Job>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl ThreadPool {
+    pub(crate) fn new(threads: usize) -> Self {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver: Arc<Mutex<Receiver<Job>>> = Arc::new(Mutex::new(receiver));
+        let workers = (0..threads.max(1))
+            .map(|_| {
+                let receiver = receiver.clone();
+                thread::spawn(move || loop {
+                    let job = receiver.lock().expect("Fail to lock job receiver").recv();
+                    match job {
+                        Ok(job) => job(),
+                        // the pool is dropped
+                        Err(_) => return,
+                    }
+                })
+            })
+            .collect();
+        ThreadPool {
+            sender: Some(sender),
+            workers,
+        }
+    }
+
+    /// Run job on a worker and send its result through result_sender,
+    /// or its panic, which doesn't kill the worker.
+    pub(crate) fn execute<T: Send + 'static>(
+        &self,
+        job: impl FnOnce() -> T + Send + 'static,
+        result_sender: Sender<thread::Result<T>>,
+    ) {
+        if let Some(sender) = &self.sender {
+            let job = move || {
+                let _ = result_sender.send(panic::catch_unwind(AssertUnwindSafe(job)));
+            };
+            sender.send(Box::new(job)).expect("Fail to send job");
+        }
+    }
+}
+
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        drop(self.sender.take());
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
@@ -1,83 +1,590 @@
+use crate::config::*;
+use crate::pool::ThreadPool;
 use crate::server::*;
-use std::time::Instant;
+use std::collections::HashMap;
+use std::ops::Range;
+use std::panic;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+
+/// The session passed to Subscriber::on_start():
+/// - socket_path: the socket path of the server
+/// - probes: the descriptions of the probes added to the publisher
+#[derive(Clone, Debug)]
+pub struct SessionInfo {
+    pub socket_path: String,
+    pub probes: HashMap<u32, ProbeInfo>,
+}
+
+/// The summary passed to Subscriber::on_shutdown():
+/// - cycles: the last complete cycle, None if Bluesim sent no message
+/// - messages: the number of messages received from Bluesim
+/// - stopped: the stop was requested by Rust
+#[derive(Clone, Debug)]
+pub struct Summary {
+    pub cycles: Option<u32>,
+    pub messages: u64,
+    pub stopped: bool,
+}
+
+/// The context of a complete cycle passed to Subscriber::update_with_context().
+pub struct CycleContext<'a> {
+    cycle: u32,
+    server: &'a mut B2RServer,
+}
+
+impl CycleContext<'_> {
+    /// return the cycle being updated
+    pub fn cycle(&self) -> u32 {
+        self.cycle
+    }
+
+    /// return the description of the probe with id
+    pub fn probe(&self, id: u32) -> Option<&ProbeInfo> {
+        self.server.probe(id)
+    }
+
+    /// return the descriptions of all the probes
+    pub fn probes(&self) -> &HashMap<u32, ProbeInfo> {
+        self.server.probes()
+    }
+
+    /// Send a message to the probe with ID "id", available from the next cycle.
+    pub fn put(&mut self, id: u32, message: Vec<u8>) {
+        self.server.put(id, message);
+    }
+
+    /// Send a message to the probe with ID "id", available from cycle.
+    pub fn put_at(&mut self, id: u32, cycle: u32, message: Vec<u8>) {
+        self.server.put_at(id, cycle, message);
+    }
+
+    /// Send a message to the probe with ID "id", available delay_cycles after the cycle being updated.
+    pub fn put_after(&mut self, id: u32, delay_cycles: u32, message: Vec<u8>) {
+        let cycle = self.cycle.saturating_add(delay_cycles);
+        self.server.put_at(id, cycle, message);
+    }
+
+    /// Request Bluesim to stop after the cycle.
+    pub fn stop(&mut self) {
+        self.server.request_stop();
+    }
+}
 
 /// The Subscriber can be used to listen to messages from specific probes
 pub trait Subscriber {
     /// called when a new cycle's messages arrived,
     /// takes the Subscribe probes's message sent in the cycle
     /// the return value will be put into the server
-    fn update(&mut self, messages: Vec<B2RMessage>) -> Vec<R2BMessage>;
+    fn update(&mut self, _messages: Vec<B2RMessage>) -> Vec<R2BMessage> {
+        Vec::new()
+    }
     /// Specify the ID of the subscribed probe.
-    fn subscribed_ids(&self) -> Vec<u32>;
+    /// Queried once by B2RPublisher::add_subscriber().
+    fn subscribed_ids(&self) -> Vec<u32> {
+        Vec::new()
+    }
+
+    /// called when a new cycle's messages arrived, calls update() by default.
+    /// Implement it instead of update() to put messages or stop Bluesim through the context.
+    fn update_with_context(&mut self, messages: Vec<B2RMessage>, context: &mut CycleContext) {
+        for put_message in self.update(messages) {
+            context.put(put_message.id, put_message.message);
+        }
+    }
+    /// called before the server starts.
+    fn on_start(&mut self, _session: &SessionInfo) {}
+    /// called after all the subscribers are updated for the cycle.
+    fn on_cycle_end(&mut self, _cycle: u32) {}
+    /// called after Bluesim shuts down the server.
+    fn on_shutdown(&mut self, _summary: &Summary) {}
+}
+
+/// The subscribed messages of a cycle passed to ParallelSubscriber::update(),
+/// the messages of the cycle are shared by all the parallel subscribers instead of cloned.
+#[derive(Clone)]
+pub struct SharedMessages {
+    messages: Arc<[B2RMessage]>,
+    indices: Vec<usize>,
+}
+
+impl SharedMessages {
+    /// return an iterator over the subscribed messages
+    pub fn iter(&self) -> impl Iterator<Item = &B2RMessage> {
+        self.indices.iter().map(|index| &self.messages[*index])
+    }
+
+    /// return the number of the subscribed messages
+    pub fn len(&self) -> usize {
+        self.indices.len()
+    }
+
+    /// return true if there is no subscribed message
+    pub fn is_empty(&self) -> bool {
+        self.indices.is_empty()
+    }
+
+    /// return all the messages of the cycle, including the unsubscribed ones
+    pub fn cycle_messages(&self) -> &Arc<[B2RMessage]> {
+        &self.messages
+    }
+}
+
+/// The context of a complete cycle passed to ParallelSubscriber::update().
+/// The messages put through it are merged into the server in the order of the subscribers after the update.
+pub struct ParallelContext {
+    cycle: u32,
+    probes: Arc<HashMap<u32, ProbeInfo>>,
+    puts: Vec<(u32, R2BMessage)>,
+    stop: bool,
+}
+
+impl ParallelContext {
+    /// return the cycle being updated
+    pub fn cycle(&self) -> u32 {
+        self.cycle
+    }
+
+    /// return the description of the probe with id
+    pub fn probe(&self, id: u32) -> Option<&ProbeInfo> {
+        self.probes.get(&id)
+    }
+
+    /// return the descriptions of all the probes
+    pub fn probes(&self) -> &HashMap<u32, ProbeInfo> {
+        &self.probes
+    }
+
+    /// Send a message to the probe with ID "id", available from the next cycle.
+    pub fn put(&mut self, id: u32, message: Vec<u8>) {
+        self.put_at(id, 0, message);
+    }
+
+    /// Send a message to the probe with ID "id", available from cycle.
+    pub fn put_at(&mut self, id: u32, cycle: u32, message: Vec<u8>) {
+        self.puts.push((cycle, R2BMessage { id, message }));
+    }
+
+    /// Send a message to the probe with ID "id", available delay_cycles after the cycle being updated.
+    pub fn put_after(&mut self, id: u32, delay_cycles: u32, message: Vec<u8>) {
+        let cycle = self.cycle.saturating_add(delay_cycles);
+        self.put_at(id, cycle, message);
+    }
+
+    /// Request Bluesim to stop after the cycle.
+    pub fn stop(&mut self) {
+        self.stop = true;
+    }
+}
+
+/// A Subscriber that runs on the thread pool of the publisher,
+/// added by B2RPublisher::add_parallel_subscriber().
+/// The parallel subscribers of a cycle are updated concurrently.
+pub trait ParallelSubscriber: Send {
+    /// called on a thread of the pool when a new cycle's messages arrived,
+    /// takes the Subscribe probes's message sent in the cycle
+    fn update(&mut self, messages: SharedMessages, context: &mut ParallelContext);
+    /// Specify the ID of the subscribed probe.
+    /// Queried once by B2RPublisher::add_parallel_subscriber().
+    fn subscribed_ids(&self) -> Vec<u32> {
+        Vec::new()
+    }
+    /// called before the server starts.
+    fn on_start(&mut self, _session: &SessionInfo) {}
+    /// called after all the subscribers are updated for the cycle.
+    fn on_cycle_end(&mut self, _cycle: u32) {}
+    /// called after Bluesim shuts down the server.
+    fn on_shutdown(&mut self, _summary: &Summary) {}
+}
+
+/// The probes subscribed by a subscriber, a message matches if its ID matches any of
+/// the IDs, the ranges or the probe name globs, where '*' matches any string and '?' matches a character.
+/// The names are described by B2RPublisher::add_probe().
+#[derive(Clone, Debug, Default)]
+pub struct IdFilter {
+    ids: Vec<u32>,
+    ranges: Vec<Range<u32>>,
+    names: Vec<String>,
+}
+
+impl IdFilter {
+    /// Make a filter matches nothing.
+    pub fn new() -> Self {
+        IdFilter::default()
+    }
+
+    /// Make a filter matches all the probes.
+    pub fn all() -> Self {
+        IdFilter::new().range(0..u32::MAX).id(u32::MAX)
+    }
+
+    /// Match the probe with id.
+    pub fn id(mut self, id: u32) -> Self {
+        self.ids.push(id);
+        self
+    }
+
+    /// Match the probes with the ids.
+    pub fn ids(mut self, ids: impl IntoIterator<Item = u32>) -> Self {
+        self.ids.extend(ids);
+        self
+    }
+
+    /// Match the probes with the IDs in range.
+    pub fn range(mut self, range: Range<u32>) -> Self {
+        self.ranges.push(range);
+        self
+    }
+
+    /// Match the probes whose names match the glob.
+    pub fn name(mut self, glob: &str) -> Self {
+        self.names.push(glob.to_string());
+        self
+    }
+
+    /// return true if the probe with id matches the filter.
+    pub fn matches(&self, id: u32, probes: &HashMap<u32, ProbeInfo>) -> bool {
+        self.ids.contains(&id)
+            || self.ranges.iter().any(|range| range.contains(&id))
+            || probes.get(&id).is_some_and(|probe| {
+                self.names
+                    .iter()
+                    .any(|glob| glob_match(glob.as_bytes(), probe.name.as_bytes()))
+            })
+    }
+}
+
+impl From<Vec<u32>> for IdFilter {
+    fn from(ids: Vec<u32>) -> Self {
+        IdFilter::new().ids(ids)
+    }
+}
+
+fn glob_match(glob: &[u8], name: &[u8]) -> bool {
+    match (glob.first(), name.first()) {
+        (None, None) => true,
+        (Some(b'*'), _) => {
+            glob_match(&glob[1..], name) || (!name.is_empty() && glob_match(glob, &name[1..]))
+        }
+        (Some(b'?'), Some(_)) => glob_match(&glob[1..], &name[1..]),
+        (Some(g), Some(n)) if g == n => glob_match(&glob[1..], &name[1..]),
+        _ => false,
+    }
+}
+
+#[derive(Default)]
+struct SubscriptionState {
+    paused: AtomicBool,
+    removed: AtomicBool,
+}
+
+/// A handle returned by B2RPublisher::add_subscriber() to control the subscription.
+/// It can be cloned and moved into other subscribers, e.g. to resume a monitor after a trigger fires.
+#[derive(Clone)]
+pub struct SubscriptionHandle {
+    state: Arc<SubscriptionState>,
+}
+
+impl SubscriptionHandle {
+    /// Remove the subscriber from the publisher before the next cycle,
+    /// the subscriber won't be updated or notified any more.
+    pub fn remove(&self) {
+        self.state.removed.store(true, Ordering::Release);
+    }
+
+    /// Stop updating the subscriber from the next cycle,
+    /// a paused subscriber is still notified by on_shutdown().
+    pub fn pause(&self) {
+        self.state.paused.store(true, Ordering::Release);
+    }
+
+    /// Resume updating the subscriber from the next cycle.
+    pub fn resume(&self) {
+        self.state.paused.store(false, Ordering::Release);
+    }
+
+    /// return true if the subscriber is paused
+    pub fn is_paused(&self) -> bool {
+        self.state.paused.load(Ordering::Acquire)
+    }
+
+    /// return true if the subscriber is removed
+    pub fn is_removed(&self) -> bool {
+        self.state.removed.load(Ordering::Acquire)
+    }
+}
+
+enum SubscriberKind {
+    Sequential(Box<dyn Subscriber>),
+    Parallel(Arc<Mutex<Box<dyn ParallelSubscriber>>>),
+}
+
+impl SubscriberKind {
+    fn on_start(&mut self, session: &SessionInfo) {
+        match self {
+            SubscriberKind::Sequential(subscriber) => subscriber.on_start(session),
+            SubscriberKind::Parallel(subscriber) => lock_parallel(subscriber).on_start(session),
+        }
+    }
+
+    fn on_cycle_end(&mut self, cycle: u32) {
+        match self {
+            SubscriberKind::Sequential(subscriber) => subscriber.on_cycle_end(cycle),
+            SubscriberKind::Parallel(subscriber) => lock_parallel(subscriber).on_cycle_end(cycle),
+        }
+    }
+
+    fn on_shutdown(&mut self, summary: &Summary) {
+        match self {
+            SubscriberKind::Sequential(subscriber) => subscriber.on_shutdown(summary),
+            SubscriberKind::Parallel(subscriber) => lock_parallel(subscriber).on_shutdown(summary),
+        }
+    }
+}
+
+fn lock_parallel(
+    subscriber: &Mutex<Box<dyn ParallelSubscriber>>,
+) -> std::sync::MutexGuard<'_, Box<dyn ParallelSubscriber>> {
+    subscriber.lock().expect("Fail to lock parallel subscriber")
+}
+
+struct Subscription {
+    subscriber: SubscriberKind,
+    filter: IdFilter,
+    state: Arc<SubscriptionState>,
 }
 
 /// A wrapper for B2RServer that to used the server and getter in event driven style
 pub struct B2RPublisher {
     server: B2RServer,
     cycle_getter: CycleGetter,
-    subscribers: Vec<Box<dyn Subscriber>>,
+    subscriptions: Vec<Subscription>,
+    threads: Option<usize>,
 }
 
 impl B2RPublisher {
     /// Creates a new B2RPublisher instance with the specified socket path.
     pub fn new_with(path: &str) -> Self {
         let server = B2RServer::new_with(path);
-        let cycle_getter = CycleGetter::new(&server);
+        let cycle_getter = CycleGetter::new_synchronous(&server);
         B2RPublisher {
             server,
             cycle_getter,
-            subscribers: Vec::new(),
+            subscriptions: Vec::new(),
+            threads: None,
         }
     }
 
-    /// Adds a subscriber to the publisher.
-    pub fn add_subscriber(&mut self, subscriber: impl Subscriber + 'static) {
-        self.subscribers.push(Box::new(subscriber));
+    /// Adds a subscriber to the publisher, subscribing the probes of its subscribed_ids().
+    pub fn add_subscriber(&mut self, subscriber: impl Subscriber + 'static) -> SubscriptionHandle {
+        let filter = IdFilter::from(subscriber.subscribed_ids());
+        self.add_subscriber_with(filter, subscriber)
+    }
+
+    /// Adds a subscriber to the publisher, subscribing the probes matching filter.
+    pub fn add_subscriber_with(
+        &mut self,
+        filter: IdFilter,
+        subscriber: impl Subscriber + 'static,
+    ) -> SubscriptionHandle {
+        self.subscribe(filter, SubscriberKind::Sequential(Box::new(subscriber)))
+    }
+
+    /// Adds a parallel subscriber to the publisher, subscribing the probes of its subscribed_ids().
+    pub fn add_parallel_subscriber(
+        &mut self,
+        subscriber: impl ParallelSubscriber + 'static,
+    ) -> SubscriptionHandle {
+        let filter = IdFilter::from(subscriber.subscribed_ids());
+        self.add_parallel_subscriber_with(filter, subscriber)
+    }
+
+    /// Adds a parallel subscriber to the publisher, subscribing the probes matching filter.
+    pub fn add_parallel_subscriber_with(
+        &mut self,
+        filter: IdFilter,
+        subscriber: impl ParallelSubscriber + 'static,
+    ) -> SubscriptionHandle {
+        let subscriber: Box<dyn ParallelSubscriber> = Box::new(subscriber);
+        self.subscribe(
+            filter,
+            SubscriberKind::Parallel(Arc::new(Mutex::new(subscriber))),
+        )
+    }
+
+    /// Set the number of threads running the parallel subscribers,
+    /// the number of available CPUs by default.
+    pub fn set_threads(&mut self, threads: usize) {
+        self.threads = Some(threads);
+    }
+
+    fn subscribe(&mut self, filter: IdFilter, subscriber: SubscriberKind) -> SubscriptionHandle {
+        let state = Arc::new(SubscriptionState::default());
+        self.subscriptions.push(Subscription {
+            subscriber,
+            filter,
+            state: state.clone(),
+        });
+        SubscriptionHandle { state }
+    }
+
+    /// Adds a paused subscriber, which is updated after the handle resumes it.
+    pub fn add_paused_subscriber_with(
+        &mut self,
+        filter: IdFilter,
+        subscriber: impl Subscriber + 'static,
+    ) -> SubscriptionHandle {
+        let handle = self.add_subscriber_with(filter, subscriber);
+        handle.pause();
+        handle
+    }
+
+    /// Describe the probe with info.id for the subscribers.
+    pub fn add_probe(&mut self, info: ProbeInfo) {
+        self.server.add_probe(info);
     }
 
     /// Serves the server and starts processing messages.
+    /// The subscribers are updated once for every complete cycle,
+    /// and the messages they return are available to Bluesim from the next cycle.
+    /// The parallel subscribers are updated on the thread pool first,
+    /// then the sequential subscribers are updated and the messages are put in the order of the subscribers.
+    /// Returns when Bluesim shuts down the server.
     pub fn serve(&mut self) {
+        let session = SessionInfo {
+            socket_path: self.server.socket_path().to_string(),
+            probes: self.server.probes().clone(),
+        };
+        for subscription in &mut self.subscriptions {
+            subscription.subscriber.on_start(&session);
+        }
+        let probes = Arc::new(session.probes);
+        let pool = self
+            .subscriptions
+            .iter()
+            .any(|subscription| matches!(subscription.subscriber, SubscriberKind::Parallel(_)))
+            .then(|| {
+                let threads = self.threads.unwrap_or_else(|| {
+                    thread::available_parallelism().map_or(1, |threads| threads.get())
+                });
+                ThreadPool::new(threads)
+            });
+
         let handle = self.server.serve();
-        let mut cycle: u32 = 0;
-        loop {
-            let mut messages: Vec<B2RMessage> = Vec::new();
-
-            // get all messages in a cycle
-            let start_time = Instant::now();
-            loop {
-                let server_cycle = self.server.current_cycle();
-                if server_cycle > cycle || start_time.elapsed().as_secs() >= 1 {
-                    cycle = server_cycle;
-                    break;
-                }
-                let cycle_message = self.cycle_getter.get_cycle_message();
-                // need to get again if the current cycle messages didn't end
-                messages.extend(cycle_message);
-            }
+        let mut summary = Summary {
+            cycles: None,
+            messages: 0,
+            stopped: false,
+        };
+        while let Some(messages) = self.cycle_getter.next_complete_cycle() {
+            let cycle = messages[0].cycles;
+            summary.cycles = Some(cycle);
+            summary.messages += messages.len() as u64;
+            let messages: Arc<[B2RMessage]> = messages.into();
 
-            // call update for the subscribers
-            for subscriber in &mut self.subscribers {
-                let subscribed_ids = subscriber.subscribed_ids();
+            self.subscriptions
+                .retain(|subscription| !subscription.state.removed.load(Ordering::Acquire));
+            let updated: Vec<bool> = self
+                .subscriptions
+                .iter()
+                .map(|subscription| !subscription.state.paused.load(Ordering::Acquire))
+                .collect();
 
-                let subscribed_messages = messages
-                    .iter()
-                    .filter(|message| subscribed_ids.contains(&message.id))
-                    .cloned()
-                    .collect();
+            // start the updates of the parallel subscribers
+            let (result_sender, result_receiver) = mpsc::channel();
+            let mut parallel_updates = 0;
+            for (index, subscription) in self.subscriptions.iter().enumerate() {
+                let (SubscriberKind::Parallel(subscriber), Some(pool), true) =
+                    (&subscription.subscriber, &pool, updated[index])
+                else {
+                    continue;
+                };
+                let shared_messages = SharedMessages {
+                    messages: messages.clone(),
+                    indices: (0..messages.len())
+                        .filter(|i| subscription.filter.matches(messages[*i].id, &probes))
+                        .collect(),
+                };
+                let mut context = ParallelContext {
+                    cycle,
+                    probes: probes.clone(),
+                    puts: Vec::new(),
+                    stop: false,
+                };
+                let subscriber = subscriber.clone();
+                pool.execute(
+                    move || {
+                        lock_parallel(&subscriber).update(shared_messages, &mut context);
+                        (index, context)
+                    },
+                    result_sender.clone(),
+                );
+                parallel_updates += 1;
+            }
+            drop(result_sender);
+            let mut results: Vec<Option<ParallelContext>> =
+                self.subscriptions.iter().map(|_| None).collect();
+            for _ in 0..parallel_updates {
+                let result = result_receiver
+                    .recv()
+                    .expect("Fail to receive the parallel result");
+                // propagate the panic of a parallel subscriber
+                let (index, context) = result.unwrap_or_else(|panic| panic::resume_unwind(panic));
+                results[index] = Some(context);
+            }
 
-                let put_messages = subscriber.update(subscribed_messages);
+            // call update for the sequential subscribers and merge the results in order
+            for (index, subscription) in self.subscriptions.iter_mut().enumerate() {
+                if !updated[index] {
+                    continue;
+                }
+                match &mut subscription.subscriber {
+                    SubscriberKind::Sequential(subscriber) => {
+                        let subscribed_messages = messages
+                            .iter()
+                            .filter(|message| {
+                                subscription
+                                    .filter
+                                    .matches(message.id, self.server.probes())
+                            })
+                            .cloned()
+                            .collect();
 
-                for put_message in put_messages {
-                    self.server.put(put_message.id, put_message.message);
+                        let mut context = CycleContext {
+                            cycle,
+                            server: &mut self.server,
+                        };
+                        subscriber.update_with_context(subscribed_messages, &mut context);
+                    }
+                    SubscriberKind::Parallel(_) => {
+                        let Some(context) = results[index].take() else {
+                            continue;
+                        };
+                        for (available, put_message) in context.puts {
+                            self.server
+                                .put_at(put_message.id, available, put_message.message);
+                        }
+                        if context.stop {
+                            self.server.request_stop();
+                        }
+                    }
                 }
             }
-
-            //stopped when there are no more message
-            if !self.server.running() {
-                break;
+            for (subscription, updated) in self.subscriptions.iter_mut().zip(updated) {
+                if updated {
+                    subscription.subscriber.on_cycle_end(cycle);
+                }
             }
         }
 
         let _ = handle.join();
+        summary.cycles = summary.cycles.max(self.server.completed_cycle());
+        summary.stopped = self.server.stop_requested();
+        for subscription in &mut self.subscriptions {
+            if !subscription.state.removed.load(Ordering::Acquire) {
+                subscription.subscriber.on_shutdown(&summary);
+            }
+        }
     }
 }
@@ -1,42 +1,47 @@
-use super::{B2RMessage, B2RServer};
-use std::collections::{HashMap, VecDeque};
-use std::sync::{Arc, Mutex};
+use super::inbox::InboxReceiver;
+use super::{B2RMessage, B2RServer, CycleBarrier};
+use crate::config::*;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
 
-/// A getter to get message from the bluesim by id
+/// A getter to get message from the bluesim by id.
+/// Every getter receives its own copy of the messages sent after it's created,
+/// the messages received before any getter is created are taken by the first getter.
 pub struct IDGetter {
-    b2r_cache: Arc<Mutex<HashMap<u32, VecDeque<B2RMessage>>>>,
+    inbox: InboxReceiver,
 }
 
 impl IDGetter {
     /// Crate a new getter bind to the given server
     pub fn new(server: &B2RServer) -> Self {
         IDGetter {
-            b2r_cache: server.b2r_cache.clone(),
+            inbox: server.dispatcher.subscribe(None),
         }
     }
+
+    /// Crate a new getter bind to the given server, only receives the messages from the probes with ids.
+    pub fn with_ids(server: &B2RServer, ids: &[u32]) -> Self {
+        IDGetter {
+            inbox: server.dispatcher.subscribe(Some(ids)),
+        }
+    }
+
     /// Return the earliest message from the probe with id.
     /// This function will block until there is a message available for retrieval.
+    /// Panics if Bluesim has shut down the server and there are no more messages.
     pub fn get(&mut self, id: u32) -> B2RMessage {
-        loop {
-            let mut b2r_cache = self.b2r_cache.lock().expect("Fail to lock b2r_cache");
-            if let Some(queue) = b2r_cache.get_mut(&id) {
-                if let Some(b2r_message) = queue.pop_front() {
-                    return b2r_message;
-                }
-            }
-            drop(b2r_cache);
-        }
+        self.inbox
+            .pop_wait(id)
+            .expect("Bluesim has shut down the server")
     }
 
     /// Get all message send by the probe with id.
     pub fn get_id_all(&mut self, id: u32) -> Vec<B2RMessage> {
-        let mut b2r_cache = self.b2r_cache.lock().expect("Fail to lock b2r_cache");
+        let mut inbox = self.inbox.lock();
         let mut messages: Vec<B2RMessage> = Vec::new();
-
-        if let Some(queue) = b2r_cache.get_mut(&id) {
-            while let Some(b2r_message) = queue.pop_front() {
-                messages.push(b2r_message);
-            }
+        while let Some(b2r_message) = inbox.pop_front(id) {
+            messages.push(b2r_message);
         }
         messages
     }
@@ -44,63 +49,218 @@ impl IDGetter {
     /// Return the earliest message from the probe with id.
     /// This function will return None if there is no message available for retrieval.
     pub fn try_get(&mut self, id: u32) -> Option<B2RMessage> {
-        let mut b2r_cache = self.b2r_cache.lock().expect("Fail to lock b2r_cache");
-        if let Some(queue) = b2r_cache.get_mut(&id) {
-            if let Some(b2r_message) = queue.pop_front() {
-                return Some(b2r_message);
-            }
-        }
-        None
+        self.inbox.lock().pop_front(id)
     }
 }
 
 /// A getter that retrieves messages from bluesim sequentially according to cycles.
 pub struct CycleGetter {
-    b2r_cache: Arc<Mutex<HashMap<u32, VecDeque<B2RMessage>>>>,
+    inbox: InboxReceiver,
+    cycle_barrier: Arc<CycleBarrier>,
+    synchronous: bool,
 }
 
 impl CycleGetter {
     /// Crate a new getter bind to the given server
     pub fn new(server: &B2RServer) -> Self {
         CycleGetter {
-            b2r_cache: server.b2r_cache.clone(),
+            inbox: server.dispatcher.subscribe(None),
+            cycle_barrier: server.cycle_barrier.clone(),
+            synchronous: false,
         }
     }
 
+    /// Crate a new getter bind to the given server,
+    /// Bluesim waits at the end of every cycle until the getter has taken all the messages of the cycle
+    /// and calls next_complete_cycle() again.
+    /// So the messages put into the server before that are available to the next cycle.
+    /// There should be only one synchronous getter for a server.
+    pub fn new_synchronous(server: &B2RServer) -> Self {
+        server.cycle_barrier.lock().synchronous = true;
+        let mut cycle_getter = CycleGetter::new(server);
+        cycle_getter.synchronous = true;
+        cycle_getter
+    }
+
     /// Get all the messages sent by the earliest cycle.
     /// If there are no messages available, it will return an empty Vec.
     pub fn get_cycle_message(&mut self) -> Vec<B2RMessage> {
-        let mut min_cycles = u32::MAX;
-        let mut messages: Vec<B2RMessage> = Vec::new();
-        let mut b2r_cache = self.b2r_cache.lock().expect("Fail to lock b2r_cache");
-        for queue in b2r_cache.values() {
-            if let Some(b2r_message) = queue.front() {
-                if b2r_message.cycles < min_cycles {
-                    min_cycles = b2r_message.cycles;
-                }
-            }
+        let mut inbox = self.inbox.lock();
+        match inbox.earliest_cycle() {
+            Some(cycle) => inbox.pop_cycle(cycle),
+            None => Vec::new(),
         }
-        for queue in b2r_cache.values_mut() {
-            if let Some(b2r_message) = queue.front() {
-                if b2r_message.cycles == min_cycles {
-                    messages.push(queue.pop_front().expect("front error"));
+    }
+
+    /// Get all the messages sent by the earliest cycle after Bluesim reports the cycle complete.
+    /// This function will block until the earliest cycle is complete.
+    /// Return None if Bluesim has shut down the server and there are no more messages.
+    pub fn next_complete_cycle(&mut self) -> Option<Vec<B2RMessage>> {
+        let mut state = self.cycle_barrier.lock();
+        loop {
+            let finished = state.finished;
+            let completed = state.completed;
+            let mut inbox = self.inbox.lock();
+            if let Some(cycle) = inbox.earliest_cycle() {
+                if finished || completed.is_some_and(|completed| cycle <= completed) {
+                    return Some(inbox.pop_cycle(cycle));
                 }
             }
+            if finished {
+                return None;
+            }
+            drop(inbox);
+            // all the complete cycles are taken, release Bluesim
+            if state.released < completed {
+                state.released = completed;
+                self.cycle_barrier.condvar.notify_all();
+            }
+            state = self.cycle_barrier.wait(state);
         }
-        messages
     }
 }
 
+impl Drop for CycleGetter {
+    fn drop(&mut self) {
+        // Bluesim no longer waits for a dropped getter
+        if self.synchronous {
+            self.cycle_barrier.detach_synchronous();
+        }
+    }
+}
+
+/// The occupancy of a fifo probed by mkFIFOFOccupancyProbe in a cycle
+/// id: ID of the fifo probe
+/// count: the number of the elements at the start of the cycle
+/// enq, deq: whether the fifo is enqueued or dequeued in the cycle
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FifoOccupancy {
+    pub id: u32,
+    pub count: u32,
+    pub enq: bool,
+    pub deq: bool,
+}
+
+/// The state of a fifo in a cycle:
+/// - Full: the fifo is full
+/// - Empty: the fifo is empty
+/// - Partial: the fifo is neither full nor empty
+/// - Missing: the fifo probe didn't report in the cycle
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FifoStatus {
+    Full,
+    Empty,
+    Partial,
+    #[default]
+    Missing,
+}
+
 /// The pipeline state
-/// cycle: the cycle of the state
-/// full_fifos: the ids of the full fifos
-/// empty_fifos: the ids of the empty fifos
+/// cycle: the cycle of the state, None if no probe reported
+/// fifos: the status of every fifo probe
 /// fire_fules: the fired rules
+/// occupancy: the occupancy of the fifos probed by mkFIFOFOccupancyProbe
+#[derive(Clone, Debug, Default)]
 pub struct PipeLineState {
-    pub cycle: u32,
-    pub full_fifos: Vec<u32>,
-    pub empty_fifos: Vec<u32>,
+    pub cycle: Option<u32>,
+    pub fifos: BTreeMap<u32, FifoStatus>,
     pub fire_rules: Vec<u32>,
+    pub occupancy: Vec<FifoOccupancy>,
+}
+
+impl PipeLineState {
+    /// Organize the messages of a cycle, such as a Subscriber gets, into a PipeLineState.
+    /// Only the messages of the earliest cycle from the fifo probes with ids fifos
+    /// and the rule probes with ids rules are used.
+    pub fn from_messages(messages: &[B2RMessage], fifos: &[u32], rules: &[u32]) -> Self {
+        let mut state = PipeLineState::missing(fifos);
+        state.cycle = messages
+            .iter()
+            .filter(|message| fifos.contains(&message.id) || rules.contains(&message.id))
+            .map(|message| message.cycles)
+            .min();
+        for message in messages {
+            if Some(message.cycles) != state.cycle {
+                continue;
+            }
+            if fifos.contains(&message.id) {
+                state.add_fifo_message(message);
+            } else if rules.contains(&message.id) {
+                state.fire_rules.push(message.id);
+            }
+        }
+        state
+    }
+
+    /// return a state without a cycle where all the fifos are missing
+    fn missing(fifos: &[u32]) -> Self {
+        PipeLineState {
+            fifos: fifos.iter().map(|id| (*id, FifoStatus::Missing)).collect(),
+            ..Default::default()
+        }
+    }
+
+    /// return the status of the fifo with id, Missing if it's not a fifo of the state
+    pub fn status(&self, id: u32) -> FifoStatus {
+        self.fifos.get(&id).copied().unwrap_or_default()
+    }
+
+    /// return the ids of the fifos with status
+    pub fn fifos_with(&self, status: FifoStatus) -> Vec<u32> {
+        self.fifos
+            .iter()
+            .filter(|(_, fifo_status)| **fifo_status == status)
+            .map(|(id, _)| *id)
+            .collect()
+    }
+
+    /// return the ids of the full fifos
+    pub fn full_fifos(&self) -> Vec<u32> {
+        self.fifos_with(FifoStatus::Full)
+    }
+
+    /// return the ids of the empty fifos
+    pub fn empty_fifos(&self) -> Vec<u32> {
+        self.fifos_with(FifoStatus::Empty)
+    }
+
+    /// return true if the rule with id fired
+    pub fn fired(&self, id: u32) -> bool {
+        self.fire_rules.contains(&id)
+    }
+
+    /// return the occupancy of the fifo with id if it is probed by mkFIFOFOccupancyProbe
+    pub fn occupancy(&self, id: u32) -> Option<&FifoOccupancy> {
+        self.occupancy.iter().find(|occupancy| occupancy.id == id)
+    }
+
+    /// Add the message of a mkFIFOFProbe or a mkFIFOFOccupancyProbe.
+    fn add_fifo_message(&mut self, message: &B2RMessage) {
+        let bytes = &message.message;
+        // the fifo message len must be 2 or 8
+        assert!(
+            bytes.len() == FIFO_INFO_BYTES || bytes.len() == FIFO_OCCUPANCY_BYTES,
+            "fifo probe {} sent a message of {} bytes",
+            message.id,
+            bytes.len()
+        );
+        let status = if bytes[0] == 0 {
+            FifoStatus::Full
+        } else if bytes[1] == 0 {
+            FifoStatus::Empty
+        } else {
+            FifoStatus::Partial
+        };
+        self.fifos.insert(message.id, status);
+        if bytes.len() == FIFO_OCCUPANCY_BYTES {
+            self.occupancy.push(FifoOccupancy {
+                id: message.id,
+                count: u32::from_le_bytes(bytes[4..8].try_into().expect("message size")),
+                enq: bytes[2] != 0,
+                deq: bytes[3] != 0,
+            });
+        }
+    }
 }
 
 /// A getter that retrieves messages from specific probes sequentially according to cycles
@@ -108,7 +268,9 @@ pub struct PipeLineState {
 pub struct PipeLineGetter {
     fifos: Vec<u32>,
     rules: Vec<u32>,
-    b2r_cache: Arc<Mutex<HashMap<u32, VecDeque<B2RMessage>>>>,
+    inbox: InboxReceiver,
+    cycle: Arc<AtomicU32>,
+    cycle_barrier: Arc<CycleBarrier>,
 }
 
 impl PipeLineGetter {
@@ -116,15 +278,20 @@ impl PipeLineGetter {
         PipeLineGetter {
             fifos: Vec::new(),
             rules: Vec::new(),
-            b2r_cache: server.b2r_cache.clone(),
+            inbox: server.dispatcher.subscribe(Some(&[])),
+            cycle: server.cycle.clone(),
+            cycle_barrier: server.cycle_barrier.clone(),
         }
     }
 
     /// add a fifo probe
     /// The fifo probe won't get data from rust, sent 2 bytes every cycle,
-    /// the fist byte is notFull second byte is notEmpty
+    /// the fist byte is notFull second byte is notEmpty.
+    /// A mkFIFOFOccupancyProbe sends 8 bytes, followed by enq fired, deq fired and the element count,
+    /// its occupancy is added to PipeLineState.occupancy.
     pub fn add_fifo_probe(&mut self, id: u32) {
         self.fifos.push(id);
+        self.inbox.add_ids(&[id]);
     }
 
     /// add a rule probe
@@ -132,56 +299,64 @@ impl PipeLineGetter {
     /// sent 1 bytes message when the rule fired
     pub fn add_rule_probe(&mut self, id: u32) {
         self.rules.push(id);
+        self.inbox.add_ids(&[id]);
+    }
+
+    /// return the ids of the added fifo probes
+    pub fn fifo_probes(&self) -> &[u32] {
+        &self.fifos
+    }
+
+    /// return the ids of the added rule probes
+    pub fn rule_probes(&self) -> &[u32] {
+        &self.rules
     }
 
     /// Read the earliest cycle messages sent by the probes labeled as "fifo" and "fired", and organize them into a PipeLineState.
+    /// The earliest cycle of the fifo and the rule messages is the cycle of the state,
+    /// only a complete cycle is read: Bluesim reported its end, a later cycle started or the server shut down.
+    /// The cycle is None if there are no messages of a complete cycle available.
     pub fn get_pipeline_state(&mut self) -> PipeLineState {
-        let mut state: PipeLineState = PipeLineState {
-            cycle: u32::MAX,
-            full_fifos: Vec::new(),
-            empty_fifos: Vec::new(),
-            fire_rules: Vec::new(),
-        };
+        let mut state = PipeLineState::missing(&self.fifos);
 
-        let mut b2r_cache: std::sync::MutexGuard<HashMap<u32, VecDeque<B2RMessage>>> =
-            self.b2r_cache.lock().expect("Fail to lock b2r_cache");
+        // read before the inbox, the messages of a complete cycle are all in the inbox
+        let barrier = self.cycle_barrier.lock();
+        let (finished, completed) = (barrier.finished, barrier.completed);
+        drop(barrier);
+        let current = self.cycle.load(Ordering::Acquire);
+        let mut inbox = self.inbox.lock();
 
-        for fifo_id in &self.fifos {
-            if let Some(messages) = b2r_cache.get(fifo_id) {
-                if let Some(first_message) = messages.front() {
-                    if first_message.cycles < state.cycle {
-                        state.cycle = first_message.cycles;
-                    }
-                }
-            }
+        let Some(cycle) = self
+            .fifos
+            .iter()
+            .chain(&self.rules)
+            .filter_map(|id| inbox.front(*id).map(|message| message.cycles))
+            .min()
+        else {
+            return state;
+        };
+        if !finished && completed.is_none_or(|completed| cycle > completed) && cycle >= current {
+            return state;
         }
+        state.cycle = Some(cycle);
 
         for fifo_id in &self.fifos {
-            if let Some(messages) = b2r_cache.get_mut(fifo_id) {
-                if let Some(first_message) = messages.front() {
-                    if first_message.cycles == state.cycle {
-                        // the fifo message len must be 2
-                        assert_eq!(first_message.message.len(), 2);
-                        let b2r_message = messages.pop_front().expect("front error");
-                        if b2r_message.message[0] == 0 {
-                            state.full_fifos.push(*fifo_id);
-                        } else if b2r_message.message[1] == 0 {
-                            state.empty_fifos.push(*fifo_id);
-                        }
-                    }
-                }
+            if inbox
+                .front(*fifo_id)
+                .is_some_and(|message| message.cycles == cycle)
+            {
+                let b2r_message = inbox.pop_front(*fifo_id).expect("front error");
+                state.add_fifo_message(&b2r_message);
             }
         }
 
         for rule_id in &self.rules {
-            if let Some(messages) = b2r_cache.get_mut(rule_id) {
-                if let Some(first_message) = messages.front() {
-                    if first_message.cycles == state.cycle {
-                        // the fifo message len must be 2
-                        assert_eq!(first_message.message.len(), 1);
-                        let _ = messages.pop_front().expect("front error");
-                        state.fire_rules.push(*rule_id);
-                    }
+            if let Some(first_message) = inbox.front(*rule_id) {
+                if first_message.cycles == cycle {
+                    // the rule message len must be 1
+                    assert_eq!(first_message.message.len(), 1);
+                    let _ = inbox.pop_front(*rule_id).expect("front error");
+                    state.fire_rules.push(*rule_id);
                 }
             }
         }
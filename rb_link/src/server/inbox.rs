@@ -0,0 +1,521 @@
+use super::spill::SpillFile;
+use super::B2RMessage;
+use crate::config::OverflowPolicy;
+use crate::store::{MessageStore, SharedStore};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::ops::Range;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, Weak};
+
+/// The number of messages a queue of a probe holds and what to do when it's full.
+#[derive(Clone, Copy)]
+pub(crate) struct Capacity {
+    pub(crate) capacity: usize,
+    pub(crate) policy: OverflowPolicy,
+}
+
+pub(crate) enum Push {
+    Pushed,
+    Dropped,
+    /// the queue is full and the policy is Block, returns the message
+    Full(B2RMessage),
+}
+
+/// A cursor over the stored messages of a probe,
+/// the indices of the messages are kept as ranges since most of them are consecutive.
+struct StoredIndices {
+    store: SharedStore,
+    id: u32,
+    ranges: VecDeque<Range<usize>>,
+    len: usize,
+}
+
+impl StoredIndices {
+    fn push(&mut self, index: usize) {
+        match self.ranges.back_mut() {
+            Some(range) if range.end == index => range.end += 1,
+            _ => self.ranges.push_back(index..index + 1),
+        }
+        self.len += 1;
+    }
+
+    fn pop(&mut self) -> Option<B2RMessage> {
+        let range = self.ranges.front_mut()?;
+        let index = range.start;
+        range.start += 1;
+        if range.start == range.end {
+            self.ranges.pop_front();
+        }
+        self.len -= 1;
+        let store = self.store.lock().expect("Fail to lock message store");
+        Some(
+            store
+                .get(self.id, index)
+                .expect("the stored message is missing"),
+        )
+    }
+}
+
+/// The messages of a probe in the order they are received.
+/// The messages overflowing the capacity may be spilled to disk,
+/// with a message store only the earliest message is kept in memory.
+#[derive(Default)]
+pub(crate) struct ProbeQueue {
+    memory: VecDeque<B2RMessage>,
+    spill: Option<SpillFile>,
+    stored: Option<StoredIndices>,
+}
+
+impl ProbeQueue {
+    pub(crate) fn new(id: u32, store: Option<&SharedStore>) -> Self {
+        ProbeQueue {
+            stored: store.map(|store| StoredIndices {
+                store: store.clone(),
+                id,
+                ranges: VecDeque::new(),
+                len: 0,
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.memory.len()
+            + self.spill.as_ref().map_or(0, SpillFile::len)
+            + self.stored.as_ref().map_or(0, |stored| stored.len)
+    }
+
+    fn front(&self) -> Option<&B2RMessage> {
+        self.memory.front()
+    }
+
+    pub(crate) fn pop_front(&mut self) -> Option<B2RMessage> {
+        let message = self.memory.pop_front();
+        // the spilled or stored messages come after the ones in memory
+        if let Some(next) = self.spill.as_mut().and_then(|spill| spill.pop()) {
+            self.memory.push_back(next);
+        } else if let Some(next) = self.stored.as_mut().and_then(|stored| stored.pop()) {
+            self.memory.push_back(next);
+        }
+        message
+    }
+
+    /// Push a message, index is its index in the message store if there is one.
+    pub(crate) fn push_back(
+        &mut self,
+        message: B2RMessage,
+        index: Option<usize>,
+        capacity: Option<Capacity>,
+    ) -> Push {
+        let mut result = Push::Pushed;
+        if let Some(Capacity { capacity, policy }) = capacity {
+            if self.len() >= capacity {
+                match policy {
+                    OverflowPolicy::Block => return Push::Full(message),
+                    OverflowPolicy::DropOldest => {
+                        self.pop_front();
+                        result = Push::Dropped;
+                    }
+                    OverflowPolicy::DropNewest => return Push::Dropped,
+                    // the stored messages are on disk already
+                    OverflowPolicy::SpillToDisk if self.stored.is_none() => {
+                        self.spill.get_or_insert_with(SpillFile::new).push(&message);
+                        return Push::Pushed;
+                    }
+                    OverflowPolicy::SpillToDisk => {}
+                }
+            }
+        }
+        match (&mut self.stored, index) {
+            (Some(stored), Some(index)) if !self.memory.is_empty() => stored.push(index),
+            _ => match self.spill.as_mut().filter(|spill| spill.len() > 0) {
+                Some(spill) => spill.push(&message),
+                None => self.memory.push_back(message),
+            },
+        }
+        result
+    }
+}
+
+/// The probes a getter is interested in.
+enum Interest {
+    All,
+    Ids(HashSet<u32>),
+}
+
+impl Interest {
+    fn contains(&self, id: u32) -> bool {
+        match self {
+            Interest::All => true,
+            Interest::Ids(ids) => ids.contains(&id),
+        }
+    }
+}
+
+/// The messages received by a getter:
+/// - queues: the messages of every probe
+/// - finished: Bluesim has shut down the server, no more messages will come
+/// - closed: the getter is dropped
+pub(crate) struct InboxState {
+    interest: Interest,
+    queues: HashMap<u32, ProbeQueue>,
+    finished: bool,
+    closed: bool,
+}
+
+impl InboxState {
+    pub(crate) fn front(&self, id: u32) -> Option<&B2RMessage> {
+        self.queues.get(&id).and_then(|queue| queue.front())
+    }
+
+    pub(crate) fn pop_front(&mut self, id: u32) -> Option<B2RMessage> {
+        self.queues.get_mut(&id).and_then(|queue| queue.pop_front())
+    }
+
+    /// return the earliest cycle of the front messages
+    pub(crate) fn earliest_cycle(&self) -> Option<u32> {
+        self.queues
+            .values()
+            .filter_map(|queue| queue.front())
+            .map(|message| message.cycles)
+            .min()
+    }
+
+    /// Pop all the front messages sent at cycle.
+    pub(crate) fn pop_cycle(&mut self, cycle: u32) -> Vec<B2RMessage> {
+        let mut messages: Vec<B2RMessage> = Vec::new();
+        for queue in self.queues.values_mut() {
+            if queue.front().is_some_and(|message| message.cycles == cycle) {
+                messages.push(queue.pop_front().expect("front error"));
+            }
+        }
+        messages
+    }
+}
+
+/// Notify the server waiting for space when the getter releases the inbox.
+pub(crate) struct InboxGuard<'a> {
+    state: MutexGuard<'a, InboxState>,
+    condvar: &'a Condvar,
+}
+
+impl std::ops::Deref for InboxGuard<'_> {
+    type Target = InboxState;
+    fn deref(&self) -> &InboxState {
+        &self.state
+    }
+}
+
+impl std::ops::DerefMut for InboxGuard<'_> {
+    fn deref_mut(&mut self) -> &mut InboxState {
+        &mut self.state
+    }
+}
+
+impl Drop for InboxGuard<'_> {
+    fn drop(&mut self) {
+        self.condvar.notify_all();
+    }
+}
+
+/// A channel from the server thread to a getter, bounded by the capacities of the probes.
+pub(crate) struct Inbox {
+    state: Mutex<InboxState>,
+    condvar: Condvar,
+}
+
+impl Inbox {
+    fn new(interest: Interest) -> Self {
+        Inbox {
+            state: Mutex::new(InboxState {
+                interest,
+                queues: HashMap::new(),
+                finished: false,
+                closed: false,
+            }),
+            condvar: Condvar::new(),
+        }
+    }
+
+    fn lock_state(&self) -> MutexGuard<'_, InboxState> {
+        self.state.lock().expect("Fail to lock inbox")
+    }
+
+    pub(crate) fn lock(&self) -> InboxGuard<'_> {
+        InboxGuard {
+            state: self.lock_state(),
+            condvar: &self.condvar,
+        }
+    }
+
+    /// Push a message, return true if a message is dropped.
+    /// Wait for the getter while the queue is full if the policy is Block.
+    fn push(
+        &self,
+        mut message: B2RMessage,
+        stored: Option<(&SharedStore, usize)>,
+        capacity: Option<Capacity>,
+    ) -> bool {
+        let mut state = self.lock_state();
+        let (store, index) = stored.unzip();
+        loop {
+            if state.closed {
+                return false;
+            }
+            let id = message.id;
+            let queue = state
+                .queues
+                .entry(id)
+                .or_insert_with(|| ProbeQueue::new(id, store));
+            match queue.push_back(message, index, capacity) {
+                Push::Pushed => break,
+                Push::Dropped => {
+                    self.condvar.notify_all();
+                    return true;
+                }
+                Push::Full(rejected) => {
+                    message = rejected;
+                    state = self.condvar.wait(state).expect("Fail to wait for inbox");
+                }
+            }
+        }
+        self.condvar.notify_all();
+        false
+    }
+
+    /// Pop the earliest message of the probe with id, wait until there is one.
+    /// Return None if Bluesim has shut down the server and there are no more messages.
+    pub(crate) fn pop_wait(&self, id: u32) -> Option<B2RMessage> {
+        let mut state = self.lock_state();
+        loop {
+            if let Some(message) = state.pop_front(id) {
+                self.condvar.notify_all();
+                return Some(message);
+            }
+            if state.finished {
+                return None;
+            }
+            state = self.condvar.wait(state).expect("Fail to wait for inbox");
+        }
+    }
+
+    fn finish(&self) {
+        // called on the unwinding server thread as well, a getter may have panicked while holding the lock
+        let mut state = self.state.lock().unwrap_or_else(|err| err.into_inner());
+        state.finished = true;
+        drop(state);
+        self.condvar.notify_all();
+    }
+
+    fn close(&self) {
+        // called on drop, the getter may be unwinding from a panic while holding the lock
+        let mut state = self.state.lock().unwrap_or_else(|err| err.into_inner());
+        state.closed = true;
+        drop(state);
+        self.condvar.notify_all();
+    }
+}
+
+/// The registered inboxes and the messages no getter is interested in.
+#[derive(Default)]
+struct Registry {
+    inboxes: Vec<Weak<Inbox>>,
+    backlog: HashMap<u32, ProbeQueue>,
+    capacities: HashMap<u32, Capacity>,
+    default_capacity: Option<Capacity>,
+    store: Option<SharedStore>,
+    finished: bool,
+}
+
+impl Registry {
+    fn capacity(&self, id: u32) -> Option<Capacity> {
+        self.capacities.get(&id).copied().or(self.default_capacity)
+    }
+}
+
+/// Fan out the messages received by the server to the getters,
+/// every getter interested in a probe receives its own copy of the messages.
+/// The messages no getter is interested in are kept until a getter becomes interested in the probe.
+/// The backlog of a probe is bounded by the capacity of the probe as well.
+#[derive(Default)]
+pub(crate) struct Dispatcher {
+    registry: Mutex<Registry>,
+    // notify the server waiting for a full backlog
+    condvar: Condvar,
+    dropped: Mutex<HashMap<u32, u64>>,
+}
+
+impl Dispatcher {
+    fn lock(&self) -> MutexGuard<'_, Registry> {
+        self.registry.lock().expect("Fail to lock dispatcher")
+    }
+
+    /// Set the capacity of the queues of the probe with id.
+    pub(crate) fn set_capacity(&self, id: u32, capacity: Capacity) {
+        self.lock().capacities.insert(id, capacity);
+    }
+
+    /// Set the capacity of the queues of the probes without their own capacity.
+    pub(crate) fn set_default_capacity(&self, capacity: Capacity) {
+        self.lock().default_capacity = Some(capacity);
+    }
+
+    /// Append the messages to store, the queues keep the indices of the messages instead.
+    pub(crate) fn set_store(&self, store: Box<dyn MessageStore>) {
+        self.lock().store = Some(Arc::new(Mutex::new(store)));
+    }
+
+    /// return the message store
+    pub(crate) fn store(&self) -> Option<SharedStore> {
+        self.lock().store.clone()
+    }
+
+    /// return the number of messages of the probe with id dropped by the full queues
+    pub(crate) fn dropped(&self, id: u32) -> u64 {
+        let dropped = self.dropped.lock().expect("Fail to lock dropped");
+        dropped.get(&id).copied().unwrap_or_default()
+    }
+
+    fn count_dropped(&self, id: u32) {
+        let mut dropped = self.dropped.lock().expect("Fail to lock dropped");
+        *dropped.entry(id).or_default() += 1;
+    }
+
+    /// Send the message to the interested inboxes.
+    pub(crate) fn dispatch(&self, mut message: B2RMessage) {
+        let mut registry = self.lock();
+        registry.inboxes.retain(|inbox| inbox.strong_count() > 0);
+        let capacity = registry.capacity(message.id);
+        let store = registry.store.clone();
+        let index = store.as_ref().map(|store| {
+            let mut store = store.lock().expect("Fail to lock message store");
+            store.append(&message);
+            store.count(message.id) - 1
+        });
+        let stored = store.as_ref().zip(index);
+        let interested: Vec<Arc<Inbox>> = registry
+            .inboxes
+            .iter()
+            .filter_map(Weak::upgrade)
+            .filter(|inbox| {
+                let state = inbox.lock_state();
+                !state.closed && state.interest.contains(message.id)
+            })
+            .collect();
+        if interested.is_empty() {
+            let id = message.id;
+            loop {
+                let queue = registry
+                    .backlog
+                    .entry(id)
+                    .or_insert_with(|| ProbeQueue::new(id, store.as_ref()));
+                match queue.push_back(message, index, capacity) {
+                    Push::Pushed => return,
+                    Push::Dropped => break,
+                    // wait for a getter to take the backlog
+                    Push::Full(rejected) => {
+                        message = rejected;
+                        registry = self
+                            .condvar
+                            .wait(registry)
+                            .expect("Fail to wait for backlog");
+                    }
+                }
+            }
+            drop(registry);
+            self.count_dropped(id);
+            return;
+        }
+        // don't block the registration of the getters while waiting for a full inbox
+        drop(registry);
+        let id = message.id;
+        let (last, others) = interested.split_last().expect("interested is not empty");
+        for inbox in others {
+            if inbox.push(message.clone(), stored, capacity) {
+                self.count_dropped(id);
+            }
+        }
+        if last.push(message, stored, capacity) {
+            self.count_dropped(id);
+        }
+    }
+
+    /// Bluesim has shut down the server, wake up the waiting getters.
+    pub(crate) fn finish(&self) {
+        let mut registry = self.lock();
+        registry.finished = true;
+        for inbox in registry.inboxes.iter().filter_map(Weak::upgrade) {
+            inbox.finish();
+        }
+    }
+
+    /// Register an inbox interested in the probes with ids, or all the probes if ids is None.
+    pub(crate) fn subscribe(self: &Arc<Self>, ids: Option<&[u32]>) -> InboxReceiver {
+        let interest = match ids {
+            Some(ids) => Interest::Ids(ids.iter().copied().collect()),
+            None => Interest::All,
+        };
+        let inbox = Arc::new(Inbox::new(interest));
+        let mut registry = self.lock();
+        registry.inboxes.push(Arc::downgrade(&inbox));
+        claim_backlog(&mut registry, &inbox);
+        self.condvar.notify_all();
+        if registry.finished {
+            inbox.finish();
+        }
+        InboxReceiver {
+            inbox,
+            dispatcher: self.clone(),
+        }
+    }
+}
+
+/// Move the backlog messages the inbox is interested in to the inbox.
+fn claim_backlog(registry: &mut Registry, inbox: &Inbox) {
+    let mut state = inbox.lock_state();
+    let ids: Vec<u32> = registry
+        .backlog
+        .keys()
+        .copied()
+        .filter(|id| state.interest.contains(*id))
+        .collect();
+    for id in ids {
+        if let Some(queue) = registry.backlog.remove(&id) {
+            // the inbox has no messages of the probe since it just becomes interested in it
+            state.queues.insert(id, queue);
+        }
+    }
+}
+
+/// The receiving end of an inbox held by a getter, the inbox is closed when it's dropped.
+pub(crate) struct InboxReceiver {
+    inbox: Arc<Inbox>,
+    dispatcher: Arc<Dispatcher>,
+}
+
+impl InboxReceiver {
+    /// Become interested in the probes with ids.
+    pub(crate) fn add_ids(&self, ids: &[u32]) {
+        let mut registry = self.dispatcher.lock();
+        {
+            let mut state = self.inbox.lock_state();
+            if let Interest::Ids(interest) = &mut state.interest {
+                interest.extend(ids);
+            }
+        }
+        claim_backlog(&mut registry, &self.inbox);
+        self.dispatcher.condvar.notify_all();
+    }
+}
+
+impl std::ops::Deref for InboxReceiver {
+    type Target = Inbox;
+    fn deref(&self) -> &Inbox {
+        &self.inbox
+    }
+}
+
+impl Drop for InboxReceiver {
+    fn drop(&mut self) {
+        self.inbox.close();
+    }
+}
@@ -1,20 +1,32 @@
 use crate::config::*;
+use crate::store::{MessageQuery, MessageStore};
 use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, VecDeque};
+use std::fs;
 use std::io::{Read, Write};
 use std::os::unix::net::{UnixListener, UnixStream};
 use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
 use std::thread::{self, JoinHandle};
-use std::{fs, usize};
 
 mod getter;
+mod inbox;
+mod spill;
 pub use getter::*;
+pub(crate) use inbox::Capacity;
+use inbox::Dispatcher;
+#[cfg(feature = "async")]
+pub(crate) use inbox::{ProbeQueue, Push};
 
 #[derive(Serialize, Deserialize)]
 pub enum GetPutMessage {
-    Get(u32),
+    /// get request from the probe with id (first) at cycles (second)
+    Get(u32, u32),
+    /// non-blocking get request, answered by a byte 1 followed by the data or a byte 0
+    TryGet(u32, u32),
     Put(B2RMessage),
+    /// all the messages of the cycle have been sent, answered by a byte CYCLE_END_ACK
+    CycleEnd(u32),
     ShutDown,
 }
 
@@ -31,21 +43,110 @@ pub struct R2BMessage {
 /// - id: ID of the probe that sent the message
 /// - cycles: Clock cycles when the message was sent
 /// - message: Sent message, where message.len() == ceil(put_t_width/8). put_t_width is the width of put_t defined in your BSV code.
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct B2RMessage {
     pub id: u32,
     pub cycles: u32,
     pub message: Vec<u8>,
 }
 
+/// The context of a get request from Bluesim, passed to the stimulus sources:
+/// - id: ID of the probe that requests the data
+/// - cycles: Clock cycles when the request was sent
+#[derive(Clone, Copy, Debug)]
+pub struct GetContext {
+    pub id: u32,
+    pub cycles: u32,
+}
+
+/// A lazy stimulus generator or a reactive handler, returns None when it has no more data.
+/// Takes the messages received in the cycle of the get request.
+type R2BSource = Box<dyn FnMut(GetContext, &[B2RMessage]) -> Option<Vec<u8>> + Send>;
+
+/// The messages to Bluesim with the cycles they become available, sorted by the cycles.
+type R2BQueue = VecDeque<(u32, R2BMessage)>;
+
+/// The state of the cycles reported complete by Bluesim:
+/// - completed: the newest complete cycle
+/// - finished: Bluesim has shut down the server, all the cycles are complete
+/// - synchronous: Bluesim waits at the end of every cycle until a synchronous getter releases it
+/// - released: the newest cycle released by the synchronous getter
+#[derive(Default)]
+struct CycleEndState {
+    completed: Option<u32>,
+    finished: bool,
+    synchronous: bool,
+    released: Option<u32>,
+}
+
+/// Notify the getters waiting for complete cycles.
+#[derive(Default)]
+struct CycleBarrier {
+    state: Mutex<CycleEndState>,
+    condvar: Condvar,
+}
+
+impl CycleBarrier {
+    fn complete(&self, cycle: u32) {
+        let mut state = self.lock();
+        if state.completed.is_none_or(|completed| cycle > completed) {
+            state.completed = Some(cycle);
+        }
+        self.condvar.notify_all();
+    }
+
+    /// Block the server thread until the synchronous getter releases the cycle.
+    fn wait_released(&self, cycle: u32) {
+        let mut state = self.lock();
+        while state.synchronous && state.released.is_none_or(|released| released < cycle) {
+            state = self.wait(state);
+        }
+    }
+
+    fn finish(&self) {
+        self.lock().finished = true;
+        self.condvar.notify_all();
+    }
+
+    fn detach_synchronous(&self) {
+        self.lock().synchronous = false;
+        self.condvar.notify_all();
+    }
+
+    fn lock(&self) -> MutexGuard<'_, CycleEndState> {
+        self.state.lock().expect("Fail to lock cycle barrier")
+    }
+
+    fn wait<'a>(&self, state: MutexGuard<'a, CycleEndState>) -> MutexGuard<'a, CycleEndState> {
+        self.condvar
+            .wait(state)
+            .expect("Fail to wait for cycle barrier")
+    }
+}
+
+/// Finish the cycle barrier and the getters when the server thread returns or panics.
+struct FinishOnDrop(Arc<CycleBarrier>, Arc<Dispatcher>);
+
+impl Drop for FinishOnDrop {
+    fn drop(&mut self) {
+        self.0.finish();
+        self.1.finish();
+    }
+}
+
 /// A server for interacting with Bluesim.
-/// Cache bidirectional data and send data upon receiving requests.
+/// Fan out the messages from Bluesim to the getters, cache the data to Bluesim and send it upon receiving requests.
 pub struct B2RServer {
     socket_path: String,
+    probes: HashMap<u32, ProbeInfo>,
     running: Arc<AtomicBool>,
+    stop: Arc<AtomicBool>,
     cycle: Arc<AtomicU32>,
-    b2r_cache: Arc<Mutex<HashMap<u32, VecDeque<B2RMessage>>>>,
-    r2b_cache: Arc<Mutex<HashMap<u32, VecDeque<R2BMessage>>>>,
+    cycle_barrier: Arc<CycleBarrier>,
+    dispatcher: Arc<Dispatcher>,
+    r2b_cache: Arc<Mutex<HashMap<u32, R2BQueue>>>,
+    r2b_invalid: Arc<Mutex<HashMap<u32, Vec<u8>>>>,
+    r2b_sources: Arc<Mutex<HashMap<u32, R2BSource>>>,
 }
 
 impl B2RServer {
@@ -54,29 +155,43 @@ impl B2RServer {
     pub fn new_with(path: &str) -> Self {
         B2RServer {
             socket_path: path.to_string(),
+            probes: HashMap::new(),
             running: Arc::new(AtomicBool::new(false)),
+            stop: Arc::new(AtomicBool::new(false)),
             cycle: Arc::new(AtomicU32::new(0)),
-            b2r_cache: Arc::new(Mutex::new(HashMap::new())),
+            cycle_barrier: Arc::new(CycleBarrier::default()),
+            dispatcher: Arc::new(Dispatcher::default()),
             r2b_cache: Arc::new(Mutex::new(HashMap::new())),
+            r2b_invalid: Arc::new(Mutex::new(HashMap::new())),
+            r2b_sources: Arc::new(Mutex::new(HashMap::new())),
         }
     }
 
     /// Start a thread to run the server.
-    /// Create a UnixListener at socket_path.
+    /// Create a UnixListener at socket_path before returning, Bluesim can connect right after,
+    /// panics in the caller if the socket can't be bound.
     /// Return the JoinHandle of that thread.
     /// This function needs to be called before running your Bluesim program.
     /// the server thread returns when bluesim called shut_down_server()
     pub fn serve(&mut self) -> JoinHandle<()> {
         // let probe_infos = self.probe_infos.clone();
         self.running.store(true, Ordering::Release);
-        let b2r_cache = self.b2r_cache.clone();
+        let dispatcher = self.dispatcher.clone();
         let r2b_cache = self.r2b_cache.clone();
+        let r2b_invalid = self.r2b_invalid.clone();
+        let r2b_sources = self.r2b_sources.clone();
         let cycle = self.cycle.clone();
+        let cycle_barrier = self.cycle_barrier.clone();
         let running = self.running.clone();
-        let socket_path = self.socket_path.clone();
+        let stop = self.stop.clone();
+        // bind before returning, Bluesim can connect as soon as serve() returns
+        let _ = fs::remove_file(self.socket_path.as_str());
+        let listener =
+            UnixListener::bind(self.socket_path.as_str()).expect("Failed to bind Unix listener");
         thread::spawn(move || {
-            let _ = fs::remove_file(socket_path.as_str());
-            let listener = UnixListener::bind(socket_path).expect("Failed to bind Unix listener");
+            let _finish = FinishOnDrop(cycle_barrier.clone(), dispatcher.clone());
+            // the messages received in the newest cycle, passed to the handlers
+            let mut cycle_messages: Vec<B2RMessage> = Vec::new();
             let mut stream = match listener.incoming().next() {
                 Some(stream_res) => stream_res.expect("Fail to connect to bluesim"),
                 None => panic!("listener returns a None"),
@@ -84,33 +199,53 @@ impl B2RServer {
             loop {
                 let message = receive_getput(&mut stream).expect("Fail to deserialize the message");
                 match message {
-                    GetPutMessage::Get(id) => {
+                    GetPutMessage::Get(id, cycles) => {
                         // println!("receive get from id {}", id);
+                        update_cycle(&cycle, cycles);
+                        retain_cycle(&mut cycle_messages, cycles);
+                        let context = GetContext { id, cycles };
                         loop {
-                            let mut r2b_cache = r2b_cache.lock().expect("Fail to lock r2b_cache");
-                            if let Some(queue) = r2b_cache.get_mut(&id) {
-                                if let Some(r2b_message) = queue.pop_front() {
-                                    stream
-                                        .write_all(&r2b_message.message)
-                                        .expect("Fail to write to socket");
-                                    drop(r2b_cache);
-                                    break;
-                                }
+                            if let Some(message) =
+                                next_r2b(&r2b_cache, &r2b_sources, context, &cycle_messages)
+                                    .or_else(|| invalid_r2b(&r2b_invalid, id))
+                            {
+                                stream.write_all(&message).expect("Fail to write to socket");
+                                break;
                             }
-                            drop(r2b_cache);
                         }
                     }
+                    GetPutMessage::TryGet(id, cycles) => {
+                        update_cycle(&cycle, cycles);
+                        retain_cycle(&mut cycle_messages, cycles);
+                        let context = GetContext { id, cycles };
+                        let response =
+                            match next_r2b(&r2b_cache, &r2b_sources, context, &cycle_messages) {
+                                Some(message) => [vec![1], message].concat(),
+                                None => vec![0],
+                            };
+                        stream
+                            .write_all(&response)
+                            .expect("Fail to write to socket");
+                    }
                     GetPutMessage::Put(b2r_message) => {
                         // println!("receive put to id {}", b2r_message.id);
                         // return if reveive message with SHUT_DOWN_ID
 
-                        let server_cycle = cycle.load(Ordering::Acquire);
-                        if b2r_message.cycles > server_cycle {
-                            cycle.store(b2r_message.cycles, Ordering::Release)
-                        }
-                        let mut b2r_cache = b2r_cache.lock().expect("Fail to lock b2r_cache");
-                        let queue = b2r_cache.entry(b2r_message.id).or_default();
-                        queue.push_back(b2r_message);
+                        update_cycle(&cycle, b2r_message.cycles);
+                        retain_cycle(&mut cycle_messages, b2r_message.cycles);
+                        cycle_messages.push(b2r_message.clone());
+                        dispatcher.dispatch(b2r_message);
+                    }
+                    GetPutMessage::CycleEnd(cycles) => {
+                        cycle_barrier.complete(cycles);
+                        cycle_barrier.wait_released(cycles);
+                        let answer = match stop.load(Ordering::Acquire) {
+                            true => CYCLE_END_STOP,
+                            false => CYCLE_END_ACK,
+                        };
+                        stream
+                            .write_all(&[answer])
+                            .expect("Fail to write to socket");
                     }
                     GetPutMessage::ShutDown => {
                         running.store(false, Ordering::Release);
@@ -125,10 +260,150 @@ impl B2RServer {
     /// Please ensure that message.len() == ceil(get_t_width/8),
     /// where get_t_width is the width of get_t defined in your BSV code.
     pub fn put(&mut self, id: u32, message: Vec<u8>) {
+        self.put_at(id, 0, message);
+    }
+
+    /// Send a message to the probe with ID "id",
+    /// which is available to the get requests sent at or after cycle.
+    /// The messages are answered in the order of their available cycles.
+    pub fn put_at(&mut self, id: u32, cycle: u32, message: Vec<u8>) {
         let r2b_message = R2BMessage { id, message };
         let mut r2b_cache = self.r2b_cache.lock().expect("Fail to lock r2b_cache");
         let queue = r2b_cache.entry(id).or_default();
-        queue.push_back(r2b_message);
+        let index = queue.partition_point(|(available, _)| *available <= cycle);
+        queue.insert(index, (cycle, r2b_message));
+    }
+
+    /// Send a message to the probe with ID "id",
+    /// which is available delay_cycles after the newest message's cycle.
+    pub fn put_after(&mut self, id: u32, delay_cycles: u32, message: Vec<u8>) {
+        let cycle = self.current_cycle().saturating_add(delay_cycles);
+        self.put_at(id, cycle, message);
+    }
+
+    /// Answer the get requests of the probe with ID "id" by message when no message is available,
+    /// instead of waiting for one. Use it as the "not valid" response of a variable-latency device.
+    pub fn set_invalid_response(&mut self, id: u32, message: Vec<u8>) {
+        let mut r2b_invalid = self.r2b_invalid.lock().expect("Fail to lock r2b_invalid");
+        r2b_invalid.insert(id, message);
+    }
+
+    /// Answer the get requests of the probe with ID "id" by calling source on the server thread.
+    /// The source is only called when there is no message put by B2RServer::put() for the probe.
+    /// Replaces the previous source of the probe.
+    pub fn set_source(
+        &mut self,
+        id: u32,
+        mut source: impl FnMut(GetContext) -> Vec<u8> + Send + 'static,
+    ) {
+        self.insert_source(id, Box::new(move |context, _| Some(source(context))));
+    }
+
+    /// Answer the get requests of the probe with ID "id" by the items of iter.
+    /// After iter runs out, the get requests wait for messages put by B2RServer::put().
+    /// Replaces the previous source of the probe.
+    pub fn set_iter_source(
+        &mut self,
+        id: u32,
+        iter: impl Iterator<Item = Vec<u8>> + Send + 'static,
+    ) {
+        let mut iter = iter.fuse();
+        self.insert_source(id, Box::new(move |_, _| iter.next()));
+    }
+
+    /// Answer the get requests of the probe with ID "id" synchronously by calling handler on the server thread.
+    /// The handler takes the messages received so far in the cycle of the get request,
+    /// a message put by a rule that Bluesim schedules after the get request is not included.
+    /// The handler is only called when there is no message put by B2RServer::put() for the probe.
+    /// Replaces the previous source of the probe.
+    pub fn set_handler(
+        &mut self,
+        id: u32,
+        mut handler: impl FnMut(GetContext, &[B2RMessage]) -> Vec<u8> + Send + 'static,
+    ) {
+        self.insert_source(
+            id,
+            Box::new(move |context, messages| Some(handler(context, messages))),
+        );
+    }
+
+    /// Remove the source or handler of the probe with ID "id".
+    pub fn remove_source(&mut self, id: u32) {
+        let mut r2b_sources = self.r2b_sources.lock().expect("Fail to lock r2b_sources");
+        r2b_sources.remove(&id);
+    }
+
+    fn insert_source(&mut self, id: u32, source: R2BSource) {
+        let mut r2b_sources = self.r2b_sources.lock().expect("Fail to lock r2b_sources");
+        r2b_sources.insert(id, source);
+    }
+
+    /// Bound the queues of the probe with ID "id" by capacity messages, every getter has its own queue.
+    /// policy decides what to do with a new message when a queue is full.
+    /// The messages no getter is interested in yet are bounded as well, a Block policy waits for a getter.
+    /// With OverflowPolicy::Block and a synchronous CycleGetter,
+    /// capacity must be more than the messages of the probe in a cycle.
+    pub fn set_capacity(&mut self, id: u32, capacity: usize, policy: OverflowPolicy) {
+        assert!(capacity > 0, "capacity must be positive");
+        self.dispatcher
+            .set_capacity(id, Capacity { capacity, policy });
+    }
+
+    /// Bound the queues of the probes without their own capacity set by B2RServer::set_capacity().
+    pub fn set_default_capacity(&mut self, capacity: usize, policy: OverflowPolicy) {
+        assert!(capacity > 0, "capacity must be positive");
+        self.dispatcher
+            .set_default_capacity(Capacity { capacity, policy });
+    }
+
+    /// Append the messages from Bluesim to store, such as a DiskLog for a long simulation.
+    /// The queues of the getters keep the indices of the messages and read them from store lazily,
+    /// so the getters work as before. Should be called before serve().
+    pub fn set_store(&mut self, store: impl MessageStore + 'static) {
+        self.dispatcher.set_store(Box::new(store));
+    }
+
+    /// Query the messages in the store set by B2RServer::set_store() without consuming them.
+    /// Return None if there is no store, set a MemoryStore to query the messages in memory.
+    pub fn query(&self) -> Option<MessageQuery> {
+        self.dispatcher.store().map(MessageQuery::with_shared)
+    }
+
+    /// return the number of messages of the probe with id dropped by the full queues,
+    /// a message dropped by several getters is counted several times
+    pub fn dropped_messages(&self, id: u32) -> u64 {
+        self.dispatcher.dropped(id)
+    }
+
+    /// Request Bluesim to stop at the end of the current cycle.
+    /// RProbe calls shut_down_server() and $finish when it receives the request.
+    pub fn request_stop(&mut self) {
+        self.stop.store(true, Ordering::Release);
+    }
+
+    /// return true if stop has been requested
+    pub fn stop_requested(&self) -> bool {
+        self.stop.load(Ordering::Acquire)
+    }
+
+    /// Describe the probe with info.id, replaces the previous description.
+    pub fn add_probe(&mut self, info: ProbeInfo) {
+        self.probes.insert(info.id, info);
+    }
+
+    /// return the description of the probe with id
+    pub fn probe(&self, id: u32) -> Option<&ProbeInfo> {
+        self.probes.get(&id)
+    }
+
+    /// return the descriptions of all the probes
+    pub fn probes(&self) -> &HashMap<u32, ProbeInfo> {
+        &self.probes
+    }
+
+    /// return the socket path of the server
+    pub fn socket_path(&self) -> &str {
+        &self.socket_path
     }
 
     /// return the newest message's cycle
@@ -136,12 +411,64 @@ impl B2RServer {
         self.cycle.load(Ordering::Acquire)
     }
 
+    /// return the newest cycle reported complete by Bluesim
+    pub fn completed_cycle(&self) -> Option<u32> {
+        self.cycle_barrier.lock().completed
+    }
+
     /// return the server running status
     pub fn running(&self) -> bool {
         self.running.load(Ordering::Acquire)
     }
 }
 
+pub(crate) fn update_cycle(cycle: &AtomicU32, cycles: u32) {
+    let server_cycle = cycle.load(Ordering::Acquire);
+    if cycles > server_cycle {
+        cycle.store(cycles, Ordering::Release)
+    }
+}
+
+/// Clear the messages of the previous cycles.
+fn retain_cycle(cycle_messages: &mut Vec<B2RMessage>, cycles: u32) {
+    if cycle_messages
+        .first()
+        .is_some_and(|message| message.cycles != cycles)
+    {
+        cycle_messages.clear();
+    }
+}
+
+/// Take the next message for a get request, the available put messages come before the source.
+fn next_r2b(
+    r2b_cache: &Mutex<HashMap<u32, R2BQueue>>,
+    r2b_sources: &Mutex<HashMap<u32, R2BSource>>,
+    context: GetContext,
+    cycle_messages: &[B2RMessage],
+) -> Option<Vec<u8>> {
+    let mut r2b_cache = r2b_cache.lock().expect("Fail to lock r2b_cache");
+    if let Some(queue) = r2b_cache.get_mut(&context.id) {
+        if queue
+            .front()
+            .is_some_and(|(available, _)| *available <= context.cycles)
+        {
+            let (_, r2b_message) = queue.pop_front().expect("front error");
+            return Some(r2b_message.message);
+        }
+    }
+    drop(r2b_cache);
+
+    let mut r2b_sources = r2b_sources.lock().expect("Fail to lock r2b_sources");
+    r2b_sources
+        .get_mut(&context.id)
+        .and_then(|source| source(context, cycle_messages))
+}
+
+fn invalid_r2b(r2b_invalid: &Mutex<HashMap<u32, Vec<u8>>>, id: u32) -> Option<Vec<u8>> {
+    let r2b_invalid = r2b_invalid.lock().expect("Fail to lock r2b_invalid");
+    r2b_invalid.get(&id).cloned()
+}
+
 fn get_msg_size(bytes: Vec<u8>) -> u32 {
     u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
 }
@@ -0,0 +1,85 @@
+use super::B2RMessage;
+use crate::config::*;
+use std::fs::{self, File, OpenOptions};
+use std::os::unix::fs::FileExt;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+static SPILL_FILES: AtomicU64 = AtomicU64::new(0);
+
+/// A FIFO of messages in a temporary file, removed when it's dropped.
+pub(crate) struct SpillFile {
+    path: PathBuf,
+    file: File,
+    read_pos: u64,
+    write_pos: u64,
+    len: usize,
+}
+
+impl SpillFile {
+    pub(crate) fn new() -> Self {
+        let path = std::env::temp_dir().join(format!(
+            "rb_link-{}-{}.spill",
+            std::process::id(),
+            SPILL_FILES.fetch_add(1, Ordering::Relaxed)
+        ));
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .expect("Fail to create spill file");
+        SpillFile {
+            path,
+            file,
+            read_pos: 0,
+            write_pos: 0,
+            len: 0,
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    pub(crate) fn push(&mut self, message: &B2RMessage) {
+        let serialized = bincode::serialize(message).expect("Serialization failed");
+        let size = serialized.len() as MsgSizeType;
+        let record = [size.to_le_bytes().as_slice(), &serialized].concat();
+        self.file
+            .write_all_at(&record, self.write_pos)
+            .expect("Fail to write spill file");
+        self.write_pos += record.len() as u64;
+        self.len += 1;
+    }
+
+    pub(crate) fn pop(&mut self) -> Option<B2RMessage> {
+        if self.len == 0 {
+            return None;
+        }
+        let mut sz_buf = [0; MSG_SIZE_BYTES];
+        self.file
+            .read_exact_at(&mut sz_buf, self.read_pos)
+            .expect("Fail to read spill file");
+        let mut buffer = vec![0; MsgSizeType::from_le_bytes(sz_buf) as usize];
+        self.file
+            .read_exact_at(&mut buffer, self.read_pos + MSG_SIZE_BYTES as u64)
+            .expect("Fail to read spill file");
+        self.read_pos += (MSG_SIZE_BYTES + buffer.len()) as u64;
+        self.len -= 1;
+        if self.len == 0 {
+            // reuse the file from the beginning
+            self.read_pos = 0;
+            self.write_pos = 0;
+            let _ = self.file.set_len(0);
+        }
+        Some(bincode::deserialize(&buffer).expect("Fail to deserialize spilled message"))
+    }
+}
+
+impl Drop for SpillFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
@@ -0,0 +1,361 @@
+use crate::config::*;
+use crate::server::B2RMessage;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::ops::Range;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+pub(crate) type SharedStore = Arc<Mutex<Box<dyn MessageStore>>>;
+
+/// A storage backend of the messages from Bluesim.
+/// The messages of every probe are indexed from 0 in the order they are appended,
+/// which is also the order of their cycles.
+pub trait MessageStore: Send {
+    /// Append a message after the messages from the same probe.
+    fn append(&mut self, message: &B2RMessage);
+
+    /// return the number of the messages from the probe with id
+    fn count(&self, id: u32) -> usize;
+
+    /// return the index-th message from the probe with id
+    fn get(&self, id: u32, index: usize) -> Option<B2RMessage>;
+
+    /// return the ids of the probes with messages
+    fn ids(&self) -> Vec<u32>;
+
+    /// return the indices of the messages from the probe with id sent in cycles
+    fn cycle_range(&self, id: u32, cycles: Range<u32>) -> Range<usize> {
+        cycle_range_by(self.count(id), cycles, |index| {
+            self.get(id, index).map(|message| message.cycles)
+        })
+    }
+}
+
+/// Binary search the indices in 0..len of the messages sent in cycles,
+/// cycle(index) returns the cycles of the index-th message.
+fn cycle_range_by(
+    len: usize,
+    cycles: Range<u32>,
+    cycle: impl Fn(usize) -> Option<u32>,
+) -> Range<usize> {
+    let partition_point = |bound: u32| {
+        let (mut low, mut high) = (0, len);
+        while low < high {
+            let mid = low + (high - low) / 2;
+            if cycle(mid).is_some_and(|cycle| cycle < bound) {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+        low
+    };
+    let start = partition_point(cycles.start);
+    start..partition_point(cycles.end).max(start)
+}
+
+/// Query the messages in a message store without consuming them,
+/// for investigating around a failure cycle after or during the simulation.
+#[derive(Clone)]
+pub struct MessageQuery {
+    store: SharedStore,
+}
+
+impl MessageQuery {
+    /// Query the messages in store, such as a DiskLog opened after the simulation.
+    pub fn new(store: impl MessageStore + 'static) -> Self {
+        MessageQuery {
+            store: Arc::new(Mutex::new(Box::new(store))),
+        }
+    }
+
+    pub(crate) fn with_shared(store: SharedStore) -> Self {
+        MessageQuery { store }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Box<dyn MessageStore>> {
+        self.store.lock().expect("Fail to lock message store")
+    }
+
+    /// return the messages from the probe with id sent in cycles
+    pub fn messages(&self, id: u32, cycles: Range<u32>) -> Vec<B2RMessage> {
+        let store = self.lock();
+        store
+            .cycle_range(id, cycles)
+            .filter_map(|index| store.get(id, index))
+            .collect()
+    }
+
+    /// return the messages from all the probes sent at cycle, sorted by the ids
+    pub fn at_cycle(&self, cycle: u32) -> Vec<B2RMessage> {
+        let store = self.lock();
+        let mut ids = store.ids();
+        ids.sort_unstable();
+        ids.into_iter()
+            .flat_map(|id| {
+                let store = &store;
+                store
+                    .cycle_range(id, cycle..cycle.saturating_add(1))
+                    .filter_map(move |index| store.get(id, index))
+            })
+            .collect()
+    }
+
+    /// return the last n messages from the probe with id
+    pub fn last_n(&self, id: u32, n: usize) -> Vec<B2RMessage> {
+        let store = self.lock();
+        let count = store.count(id);
+        (count.saturating_sub(n)..count)
+            .filter_map(|index| store.get(id, index))
+            .collect()
+    }
+
+    /// return the earliest message from the probe with id that satisfies predicate
+    pub fn find_first(
+        &self,
+        id: u32,
+        mut predicate: impl FnMut(&B2RMessage) -> bool,
+    ) -> Option<B2RMessage> {
+        let store = self.lock();
+        (0..store.count(id))
+            .filter_map(|index| store.get(id, index))
+            .find(|message| predicate(message))
+    }
+
+    /// return the number of the messages from the probe with id
+    pub fn count(&self, id: u32) -> usize {
+        self.lock().count(id)
+    }
+}
+
+/// Keep all the messages in memory.
+#[derive(Default)]
+pub struct MemoryStore {
+    messages: HashMap<u32, Vec<B2RMessage>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        MemoryStore::default()
+    }
+}
+
+impl MessageStore for MemoryStore {
+    fn append(&mut self, message: &B2RMessage) {
+        self.messages
+            .entry(message.id)
+            .or_default()
+            .push(message.clone());
+    }
+
+    fn count(&self, id: u32) -> usize {
+        self.messages.get(&id).map_or(0, Vec::len)
+    }
+
+    fn get(&self, id: u32, index: usize) -> Option<B2RMessage> {
+        self.messages.get(&id)?.get(index).cloned()
+    }
+
+    fn ids(&self) -> Vec<u32> {
+        self.messages.keys().copied().collect()
+    }
+
+    fn cycle_range(&self, id: u32, cycles: Range<u32>) -> Range<usize> {
+        let Some(messages) = self.messages.get(&id) else {
+            return 0..0;
+        };
+        let start = messages.partition_point(|message| message.cycles < cycles.start);
+        let end = messages.partition_point(|message| message.cycles < cycles.end);
+        start..end.max(start)
+    }
+}
+
+/// The default size of a segment of DiskLog.
+pub const DEFAULT_SEGMENT_BYTES: u64 = 256 << 20;
+
+// segment: u32, offset: u64, cycles: u32
+const INDEX_ENTRY_BYTES: usize = 16;
+
+/// A message log on disk, for the simulations too long to keep the messages in memory.
+/// The messages are appended to segment files "{n}.seg" of about segment_bytes bytes,
+/// the messages of a probe are indexed by "{id}.idx" with their locations and cycles,
+/// so only the open files are kept in memory.
+pub struct DiskLog {
+    dir: PathBuf,
+    segment_bytes: u64,
+    segments: Vec<File>,
+    write_pos: u64,
+    indices: HashMap<u32, (File, usize)>,
+}
+
+impl DiskLog {
+    /// Create an empty log in dir, the previous log in dir is removed.
+    pub fn create(dir: impl AsRef<Path>) -> io::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if log_file(&path).is_some() {
+                fs::remove_file(path)?;
+            }
+        }
+        Ok(DiskLog {
+            dir,
+            segment_bytes: DEFAULT_SEGMENT_BYTES,
+            segments: Vec::new(),
+            write_pos: 0,
+            indices: HashMap::new(),
+        })
+    }
+
+    /// Open the log in dir written by a previous run, new messages are appended to it.
+    pub fn open(dir: impl AsRef<Path>) -> io::Result<Self> {
+        let mut log = DiskLog {
+            dir: dir.as_ref().to_path_buf(),
+            segment_bytes: DEFAULT_SEGMENT_BYTES,
+            segments: Vec::new(),
+            write_pos: 0,
+            indices: HashMap::new(),
+        };
+        let mut segments: Vec<u32> = Vec::new();
+        for entry in fs::read_dir(&log.dir)? {
+            let path = entry?.path();
+            match log_file(&path) {
+                Some(("seg", n)) => segments.push(n),
+                Some(("idx", id)) => {
+                    let file = open_file(&path)?;
+                    let count = file.metadata()?.len() as usize / INDEX_ENTRY_BYTES;
+                    log.indices.insert(id, (file, count));
+                }
+                _ => {}
+            }
+        }
+        segments.sort_unstable();
+        if segments.iter().enumerate().any(|(i, n)| i as u32 != *n) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "missing segment in the message log",
+            ));
+        }
+        for n in segments {
+            log.segments.push(open_file(&log.segment_path(n))?);
+        }
+        if let Some(last) = log.segments.last() {
+            log.write_pos = last.metadata()?.len();
+        }
+        Ok(log)
+    }
+
+    /// Set the size of the new segments.
+    pub fn set_segment_bytes(&mut self, segment_bytes: u64) {
+        self.segment_bytes = segment_bytes;
+    }
+
+    /// return the directory of the log
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn segment_path(&self, n: u32) -> PathBuf {
+        self.dir.join(format!("{}.seg", n))
+    }
+
+    /// return (segment, offset, cycles) of the index-th message from the probe with id
+    fn entry(&self, id: u32, index: usize) -> Option<(u32, u64, u32)> {
+        let (file, count) = self.indices.get(&id)?;
+        if index >= *count {
+            return None;
+        }
+        let mut entry = [0; INDEX_ENTRY_BYTES];
+        file.read_exact_at(&mut entry, (index * INDEX_ENTRY_BYTES) as u64)
+            .expect("Fail to read message log index");
+        let segment = u32::from_le_bytes(entry[0..4].try_into().expect("entry size"));
+        let offset = u64::from_le_bytes(entry[4..12].try_into().expect("entry size"));
+        let cycles = u32::from_le_bytes(entry[12..16].try_into().expect("entry size"));
+        Some((segment, offset, cycles))
+    }
+}
+
+impl MessageStore for DiskLog {
+    fn append(&mut self, message: &B2RMessage) {
+        let serialized = bincode::serialize(message).expect("Serialization failed");
+        let size = serialized.len() as MsgSizeType;
+        let record = [size.to_le_bytes().as_slice(), &serialized].concat();
+        if self.segments.is_empty()
+            || (self.write_pos > 0 && self.write_pos + record.len() as u64 > self.segment_bytes)
+        {
+            let path = self.segment_path(self.segments.len() as u32);
+            self.segments
+                .push(open_file(&path).expect("Fail to create message log segment"));
+            self.write_pos = 0;
+        }
+        let segment = self.segments.len() as u32 - 1;
+        self.segments[segment as usize]
+            .write_all_at(&record, self.write_pos)
+            .expect("Fail to write message log");
+
+        let mut entry = [0; INDEX_ENTRY_BYTES];
+        entry[0..4].copy_from_slice(&segment.to_le_bytes());
+        entry[4..12].copy_from_slice(&self.write_pos.to_le_bytes());
+        entry[12..16].copy_from_slice(&message.cycles.to_le_bytes());
+        self.write_pos += record.len() as u64;
+        if !self.indices.contains_key(&message.id) {
+            let path = self.dir.join(format!("{}.idx", message.id));
+            let file = open_file(&path).expect("Fail to create message log index");
+            self.indices.insert(message.id, (file, 0));
+        }
+        let (file, count) = self.indices.get_mut(&message.id).expect("index is created");
+        file.write_all_at(&entry, (*count * INDEX_ENTRY_BYTES) as u64)
+            .expect("Fail to write message log index");
+        *count += 1;
+    }
+
+    fn count(&self, id: u32) -> usize {
+        self.indices.get(&id).map_or(0, |(_, count)| *count)
+    }
+
+    fn get(&self, id: u32, index: usize) -> Option<B2RMessage> {
+        let (segment, offset, _) = self.entry(id, index)?;
+        let file = &self.segments[segment as usize];
+        let mut sz_buf = [0; MSG_SIZE_BYTES];
+        file.read_exact_at(&mut sz_buf, offset)
+            .expect("Fail to read message log");
+        let mut buffer = vec![0; MsgSizeType::from_le_bytes(sz_buf) as usize];
+        file.read_exact_at(&mut buffer, offset + MSG_SIZE_BYTES as u64)
+            .expect("Fail to read message log");
+        Some(bincode::deserialize(&buffer).expect("Fail to deserialize logged message"))
+    }
+
+    fn ids(&self) -> Vec<u32> {
+        self.indices.keys().copied().collect()
+    }
+
+    fn cycle_range(&self, id: u32, cycles: Range<u32>) -> Range<usize> {
+        // only read the index
+        cycle_range_by(self.count(id), cycles, |index| {
+            self.entry(id, index).map(|(_, _, cycles)| cycles)
+        })
+    }
+}
+
+/// return the extension and the number of a segment or an index file
+fn log_file(path: &Path) -> Option<(&str, u32)> {
+    let extension = path.extension()?.to_str()?;
+    let n = path.file_stem()?.to_str()?.parse().ok()?;
+    match extension {
+        "seg" | "idx" => Some((extension, n)),
+        _ => None,
+    }
+}
+
+fn open_file(path: &Path) -> io::Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+}
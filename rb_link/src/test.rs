@@ -1,6 +1,8 @@
 use super::*;
-use std::io::Write;
+use std::collections::HashMap;
+use std::io::{Read, Write};
 use std::os::unix::net::UnixStream;
+use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::Duration;
 #[test]
@@ -92,6 +94,19 @@ fn test_connect_before_server() {
     let _ = server.serve();
 }
 
+#[test]
+fn test_connect_after_serve() {
+    let mut server = B2RServer::new_with("/tmp/test_connect_after_serve");
+    let mut id_getter = IDGetter::new(&server);
+    let _ = server.serve();
+    // no sleep, the socket is bound when serve() returns
+    let mut stream = UnixStream::connect(String::from("/tmp/test_connect_after_serve"))
+        .expect("Failed to connect to socket");
+    put(0, 0, vec![1], &mut stream);
+    assert_eq!(id_getter.get(0).message, vec![1]);
+    put_shut_down(&mut stream);
+}
+
 #[test]
 fn test_deserialize_fail() {
     let mut server = B2RServer::new_with("/tmp/test_deserialize_fail");
@@ -136,19 +151,56 @@ fn test_get_get_pipeline_state() {
     put(10, 0, vec![1], &mut stream);
     put(12, 0, vec![1], &mut stream);
     put(14, 0, vec![1], &mut stream);
+    assert_eq!(cycle_end(0, &mut stream), CYCLE_END_ACK);
 
     thread::sleep(Duration::from_micros(400));
     let state = pipe_getter.get_pipeline_state();
 
-    assert_eq!(state.cycle, 0);
-    assert_eq!(state.empty_fifos.len(), 1);
-    assert_eq!(state.full_fifos.len(), 1);
+    assert_eq!(state.cycle, Some(0));
+    assert_eq!(state.empty_fifos().len(), 1);
+    assert_eq!(state.full_fifos().len(), 1);
     assert_eq!(state.fire_rules.len(), 3);
-    assert_eq!(state.empty_fifos[0], 0);
-    assert_eq!(state.full_fifos[0], 3);
+    assert_eq!(state.empty_fifos()[0], 0);
+    assert_eq!(state.full_fifos()[0], 3);
+    assert_eq!(state.fifos_with(FifoStatus::Partial), vec![1, 2]);
     assert!(state.fire_rules.contains(&10));
     assert!(state.fire_rules.contains(&12));
     assert!(state.fire_rules.contains(&14));
+
+    // a cycle with only the rule messages, fifo 1 reports later
+    put(11, 1, vec![1], &mut stream);
+    put(1, 2, full_msg.clone(), &mut stream);
+    assert_eq!(cycle_end(2, &mut stream), CYCLE_END_ACK);
+    thread::sleep(Duration::from_micros(400));
+    let state = pipe_getter.get_pipeline_state();
+    assert_eq!(state.cycle, Some(1));
+    assert!(state.fired(11));
+    assert_eq!(state.fifos_with(FifoStatus::Missing), vec![0, 1, 2, 3]);
+    let state = pipe_getter.get_pipeline_state();
+    assert_eq!(state.cycle, Some(2));
+    assert_eq!(state.status(1), FifoStatus::Full);
+    assert_eq!(state.status(0), FifoStatus::Missing);
+    assert!(state.fire_rules.is_empty());
+
+    let state = pipe_getter.get_pipeline_state();
+    assert_eq!(state.cycle, None);
+    assert_eq!(state.fifos.len(), 4);
+
+    // a cycle delivered in two batches is read after it's complete
+    put(0, 3, empty_msg.clone(), &mut stream);
+    thread::sleep(Duration::from_micros(400));
+    assert_eq!(pipe_getter.get_pipeline_state().cycle, None);
+    put(1, 3, full_msg.clone(), &mut stream);
+    put(10, 3, vec![1], &mut stream);
+    assert_eq!(cycle_end(3, &mut stream), CYCLE_END_ACK);
+    thread::sleep(Duration::from_micros(400));
+    let state = pipe_getter.get_pipeline_state();
+    assert_eq!(state.cycle, Some(3));
+    assert_eq!(state.status(0), FifoStatus::Empty);
+    assert_eq!(state.status(1), FifoStatus::Full);
+    assert_eq!(state.fifos_with(FifoStatus::Missing), vec![2, 3]);
+    assert_eq!(state.fire_rules, vec![10]);
+    assert_eq!(pipe_getter.get_pipeline_state().cycle, None);
 }
 
 #[test]
@@ -165,6 +217,7 @@ fn test_probe_type_error() {
         .expect("Failed to connect to socket");
 
     put(0, 0, vec![0, 0, 0], &mut stream);
+    assert_eq!(cycle_end(0, &mut stream), CYCLE_END_ACK);
 
     thread::sleep(Duration::from_micros(400));
     let _state = pipe_getter.get_pipeline_state();
@@ -246,6 +299,1620 @@ fn test_publisher() {
     publisher.serve();
 }
 
+#[test]
+fn test_set_source() {
+    let mut server = B2RServer::new_with("/tmp/test_set_source");
+    server.set_source(3, |context: GetContext| {
+        (context.id + context.cycles).to_le_bytes().to_vec()
+    });
+
+    let _ = server.serve();
+    thread::sleep(Duration::from_micros(400));
+    let mut stream = UnixStream::connect(String::from("/tmp/test_set_source"))
+        .expect("Failed to connect to socket");
+
+    assert_eq!(get(3, 10, 4, &mut stream), 13u32.to_le_bytes().to_vec());
+    assert_eq!(get(3, 11, 4, &mut stream), 14u32.to_le_bytes().to_vec());
+
+    // the put messages come before the source
+    server.put(3, 7u32.to_le_bytes().to_vec());
+    assert_eq!(get(3, 12, 4, &mut stream), 7u32.to_le_bytes().to_vec());
+    assert_eq!(get(3, 13, 4, &mut stream), 16u32.to_le_bytes().to_vec());
+    assert_eq!(server.current_cycle(), 13);
+}
+
+#[test]
+fn test_set_iter_source() {
+    let mut server = B2RServer::new_with("/tmp/test_set_iter_source");
+    server.set_iter_source(0, (0..2u32).map(|i| i.to_le_bytes().to_vec()));
+
+    let _ = server.serve();
+    thread::sleep(Duration::from_micros(400));
+    let mut stream = UnixStream::connect(String::from("/tmp/test_set_iter_source"))
+        .expect("Failed to connect to socket");
+
+    assert_eq!(get(0, 0, 4, &mut stream), 0u32.to_le_bytes().to_vec());
+    assert_eq!(get(0, 1, 4, &mut stream), 1u32.to_le_bytes().to_vec());
+
+    // wait for the put messages after the iterator runs out
+    let put_thread = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(10));
+        server.put(0, 5u32.to_le_bytes().to_vec());
+    });
+    assert_eq!(get(0, 2, 4, &mut stream), 5u32.to_le_bytes().to_vec());
+    let _ = put_thread.join();
+}
+
+#[test]
+fn test_set_handler() {
+    let mut server = B2RServer::new_with("/tmp/test_set_handler");
+    // reply the sum of the messages sent in the same cycle
+    server.set_handler(7, |context: GetContext, messages: &[B2RMessage]| {
+        assert!(messages.iter().all(|msg| msg.cycles == context.cycles));
+        vec![messages.iter().map(|msg| msg.message[0]).sum()]
+    });
+
+    let _ = server.serve();
+    thread::sleep(Duration::from_micros(400));
+    let mut stream = UnixStream::connect(String::from("/tmp/test_set_handler"))
+        .expect("Failed to connect to socket");
+
+    put(5, 3, vec![1], &mut stream);
+    put(6, 3, vec![2], &mut stream);
+    assert_eq!(get(7, 3, 1, &mut stream), vec![3]);
+    put(5, 3, vec![4], &mut stream);
+    assert_eq!(get(7, 3, 1, &mut stream), vec![7]);
+    assert_eq!(get(7, 4, 1, &mut stream), vec![0]);
+    put(6, 5, vec![9], &mut stream);
+    assert_eq!(get(7, 5, 1, &mut stream), vec![9]);
+}
+
+#[test]
+fn test_memory_model() {
+    let mut server = B2RServer::new_with("/tmp/test_memory_model");
+    let mut model = MemoryModel::new(64, 4);
+    model.set_base(0x1000);
+    model.set_latency(2);
+    model.write(0x1004, &[1, 2, 3, 4]);
+    let model = model.attach(&mut server, 0, 1);
+
+    let _ = server.serve();
+    thread::sleep(Duration::from_micros(400));
+    let mut stream = UnixStream::connect(String::from("/tmp/test_memory_model"))
+        .expect("Failed to connect to socket");
+
+    // write the byte 1 and 3 of the word at 0x1004, then read it
+    put(0, 0, mem_req(true, 0x1004, 0b1010, 0xaabbccdd), &mut stream);
+    put(0, 1, mem_req(false, 0x1006, 0, 0), &mut stream);
+    assert_eq!(get(1, 1, 5, &mut stream), vec![0, 0, 0, 0, 0]);
+    assert_eq!(get(1, 2, 5, &mut stream), vec![0, 0, 0, 0, 0]);
+    assert_eq!(get(1, 3, 5, &mut stream), vec![1, 0xcc, 3, 0xaa, 1]);
+    assert_eq!(get(1, 4, 5, &mut stream), vec![0, 0, 0, 0, 0]);
+
+    // the accesses out of the memory don't stop the server
+    put(0, 5, mem_req(true, 0x2000, 0b1111, 0x11223344), &mut stream);
+    put(0, 6, mem_req(false, 0xffc, 0, 0), &mut stream);
+    assert_eq!(get(1, 8, 5, &mut stream), vec![0, 0, 0, 0, 1]);
+
+    let model = model.lock().unwrap();
+    assert_eq!(model.read(0x1004, 4), &[1, 0xcc, 3, 0xaa]);
+    assert_eq!(
+        model.bad_accesses(),
+        &[
+            BadAccess {
+                cycle: 5,
+                addr: 0x2000,
+                write: true
+            },
+            BadAccess {
+                cycle: 6,
+                addr: 0xffc,
+                write: false
+            }
+        ]
+    );
+}
+
+#[test]
+fn test_memory_model_load() {
+    let hex_path = "/tmp/test_memory_model_load.hex";
+    std::fs::write(hex_path, "// comment\n0403_0201 08070605\n@4\n0c0b0a09\n").unwrap();
+    let mut model = MemoryModel::new(32, 4);
+    model.load_hex(hex_path).unwrap();
+    assert_eq!(model.read(0, 8), &[1, 2, 3, 4, 5, 6, 7, 8]);
+    assert_eq!(model.read(16, 4), &[9, 10, 11, 12]);
+
+    // a 32-bit ELF with a PT_LOAD segment of 4 bytes at 0x10
+    let mut elf = vec![0; 0x34 + 0x20 + 4];
+    elf[0..6].copy_from_slice(&[0x7f, b'E', b'L', b'F', 1, 1]);
+    elf[0x1c..0x20].copy_from_slice(&0x34u32.to_le_bytes());
+    elf[0x2a..0x2c].copy_from_slice(&0x20u16.to_le_bytes());
+    elf[0x2c..0x2e].copy_from_slice(&1u16.to_le_bytes());
+    elf[0x34..0x38].copy_from_slice(&1u32.to_le_bytes());
+    elf[0x38..0x3c].copy_from_slice(&0x54u32.to_le_bytes());
+    elf[0x40..0x44].copy_from_slice(&0x10u32.to_le_bytes());
+    elf[0x44..0x48].copy_from_slice(&4u32.to_le_bytes());
+    elf[0x54..0x58].copy_from_slice(&[0xde, 0xad, 0xbe, 0xef]);
+    let elf_path = "/tmp/test_memory_model_load.elf";
+    std::fs::write(elf_path, elf).unwrap();
+    model.load_elf(elf_path).unwrap();
+    assert_eq!(model.read(0x10, 4), &[0xde, 0xad, 0xbe, 0xef]);
+
+    assert!(model.load_elf(hex_path).is_err());
+
+    // a segment out of the memory
+    let mut elf = std::fs::read(elf_path).unwrap();
+    elf[0x40..0x44].copy_from_slice(&0x1eu32.to_le_bytes());
+    std::fs::write(elf_path, &elf).unwrap();
+    let err = model.load_elf(elf_path).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    // a segment whose offset + filesz overflows
+    elf[0x38..0x3c].copy_from_slice(&u32::MAX.to_le_bytes());
+    elf[0x40..0x44].copy_from_slice(&0x10u32.to_le_bytes());
+    std::fs::write(elf_path, &elf).unwrap();
+    let err = model.load_elf(elf_path).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+
+    // a hex address out of the memory
+    std::fs::write(hex_path, "@8\n01020304\n").unwrap();
+    let err = model.load_hex(hex_path).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    std::fs::write(hex_path, "@ffffffffffffffff\n01020304\n").unwrap();
+    let err = model.load_hex(hex_path).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    assert_eq!(model.read(0x10, 4), &[0xde, 0xad, 0xbe, 0xef]);
+}
+
+/// pack a RMemReq#(32, 32)
+fn mem_req(write: bool, addr: u32, byte_en: u8, data: u32) -> Vec<u8> {
+    let packed: u128 =
+        (write as u128) << 68 | (addr as u128) << 36 | (byte_en as u128) << 32 | data as u128;
+    packed.to_le_bytes()[..9].to_vec()
+}
+
+#[test]
+fn test_put_at() {
+    let mut server = B2RServer::new_with("/tmp/test_put_at");
+    server.set_invalid_response(2, vec![0xff]);
+
+    let _ = server.serve();
+    thread::sleep(Duration::from_micros(400));
+    let mut stream =
+        UnixStream::connect(String::from("/tmp/test_put_at")).expect("Failed to connect to socket");
+
+    put(0, 10, vec![0], &mut stream);
+    server.put_after(2, 2, vec![1]);
+    server.put_at(2, 11, vec![7]);
+    assert_eq!(get(2, 10, 1, &mut stream), vec![0xff]);
+    assert_eq!(get(2, 11, 1, &mut stream), vec![7]);
+    assert_eq!(get(2, 11, 1, &mut stream), vec![0xff]);
+    assert_eq!(get(2, 12, 1, &mut stream), vec![1]);
+    assert_eq!(get(2, 13, 1, &mut stream), vec![0xff]);
+}
+
+#[test]
+fn test_try_get_r2b() {
+    let mut server = B2RServer::new_with("/tmp/test_try_get_r2b");
+    server.put_at(0, 5, vec![5, 5]);
+    server.put(0, vec![1, 1]);
+    server.set_invalid_response(0, vec![0xff, 0xff]);
+
+    let _ = server.serve();
+    thread::sleep(Duration::from_micros(400));
+    let mut stream = UnixStream::connect(String::from("/tmp/test_try_get_r2b"))
+        .expect("Failed to connect to socket");
+
+    assert_eq!(try_get(0, 0, 2, &mut stream), Some(vec![1, 1]));
+    assert_eq!(try_get(0, 0, 2, &mut stream), None);
+    assert_eq!(try_get(1, 0, 2, &mut stream), None);
+    assert_eq!(try_get(0, 5, 2, &mut stream), Some(vec![5, 5]));
+
+    server.set_source(1, |_| vec![3, 4]);
+    assert_eq!(try_get(1, 6, 2, &mut stream), Some(vec![3, 4]));
+}
+
+#[test]
+fn test_next_complete_cycle() {
+    let mut server = B2RServer::new_with("/tmp/test_next_complete_cycle");
+    let mut cycle_getter = CycleGetter::new(&server);
+    let _ = server.serve();
+
+    let sim = thread::spawn(|| {
+        thread::sleep(Duration::from_micros(500));
+        let mut stream = UnixStream::connect(String::from("/tmp/test_next_complete_cycle"))
+            .expect("Failed to connect to socket");
+        put(0, 0, vec![0], &mut stream);
+        put(1, 0, vec![0], &mut stream);
+        thread::sleep(Duration::from_millis(10));
+        put(2, 0, vec![0], &mut stream);
+        assert_eq!(cycle_end(0, &mut stream), CYCLE_END_ACK);
+
+        put(0, 2, vec![1], &mut stream);
+        put_shut_down(&mut stream);
+    });
+
+    let msgs = cycle_getter.next_complete_cycle().unwrap();
+    assert_eq!(msgs.len(), 3);
+    assert!(msgs.iter().all(|msg| msg.cycles == 0));
+    assert_eq!(server.completed_cycle(), Some(0));
+
+    let msgs = cycle_getter.next_complete_cycle().unwrap();
+    assert_eq!(msgs.len(), 1);
+    assert_eq!(msgs[0].cycles, 2);
+
+    assert!(cycle_getter.next_complete_cycle().is_none());
+    let _ = sim.join();
+}
+
+#[test]
+fn test_publisher_reply() {
+    let mut publisher = B2RPublisher::new_with("/tmp/test_publisher_reply");
+    // reply the double of the message to the probe 1
+    struct Doubler;
+    impl Subscriber for Doubler {
+        fn update(&mut self, messages: Vec<B2RMessage>) -> Vec<R2BMessage> {
+            messages
+                .iter()
+                .map(|message| R2BMessage {
+                    id: 1,
+                    message: vec![message.message[0] * 2],
+                })
+                .collect()
+        }
+
+        fn subscribed_ids(&self) -> Vec<u32> {
+            vec![0]
+        }
+    }
+    publisher.add_subscriber(Doubler);
+
+    let sim = thread::spawn(|| {
+        thread::sleep(Duration::from_micros(500));
+        let mut stream = UnixStream::connect(String::from("/tmp/test_publisher_reply"))
+            .expect("Failed to connect to socket");
+        for cycle in 0..10 {
+            put(0, cycle, vec![cycle as u8], &mut stream);
+            // the reply is available after the cycle end
+            cycle_end(cycle, &mut stream);
+            assert_eq!(
+                try_get(1, cycle + 1, 1, &mut stream),
+                Some(vec![cycle as u8 * 2])
+            );
+        }
+        put_shut_down(&mut stream);
+    });
+
+    publisher.serve();
+    sim.join().unwrap();
+}
+
+#[test]
+fn test_subscriber_context() {
+    let mut publisher = B2RPublisher::new_with("/tmp/test_subscriber_context");
+    publisher.add_probe(ProbeInfo {
+        id: 0,
+        name: String::from("counter"),
+        put_width: 8,
+        get_width: 8,
+    });
+
+    // echo the counter 2 cycles later and stop Bluesim when it reaches 3
+    struct Echo {
+        events: Arc<Mutex<Vec<String>>>,
+    }
+    impl Subscriber for Echo {
+        fn subscribed_ids(&self) -> Vec<u32> {
+            vec![0]
+        }
+
+        fn update_with_context(&mut self, messages: Vec<B2RMessage>, context: &mut CycleContext) {
+            assert_eq!(context.probe(0).unwrap().name, "counter");
+            for message in messages {
+                assert_eq!(message.cycles, context.cycle());
+                context.put_after(1, 2, message.message.clone());
+                if message.message[0] == 3 {
+                    context.stop();
+                }
+            }
+        }
+
+        fn on_start(&mut self, session: &SessionInfo) {
+            let mut events = self.events.lock().unwrap();
+            events.push(format!("start {}", session.probes[&0].name));
+        }
+
+        fn on_cycle_end(&mut self, cycle: u32) {
+            self.events.lock().unwrap().push(format!("end {}", cycle));
+        }
+
+        fn on_shutdown(&mut self, summary: &Summary) {
+            let mut events = self.events.lock().unwrap();
+            events.push(format!(
+                "shutdown {:?} {} {}",
+                summary.cycles, summary.messages, summary.stopped
+            ));
+        }
+    }
+    let events = Arc::new(Mutex::new(Vec::new()));
+    publisher.add_subscriber(Echo {
+        events: events.clone(),
+    });
+
+    let sim = thread::spawn(|| {
+        thread::sleep(Duration::from_micros(500));
+        let mut stream = UnixStream::connect(String::from("/tmp/test_subscriber_context"))
+            .expect("Failed to connect to socket");
+        let mut cycle = 0;
+        loop {
+            put(0, cycle, vec![cycle as u8], &mut stream);
+            if cycle >= 2 {
+                assert_eq!(get(1, cycle, 1, &mut stream), vec![cycle as u8 - 2]);
+            }
+            if cycle_end(cycle, &mut stream) == CYCLE_END_STOP {
+                break;
+            }
+            cycle += 1;
+        }
+        assert_eq!(cycle, 3);
+        put_shut_down(&mut stream);
+    });
+
+    publisher.serve();
+    sim.join().unwrap();
+    assert_eq!(
+        *events.lock().unwrap(),
+        vec![
+            "start counter",
+            "end 0",
+            "end 1",
+            "end 2",
+            "end 3",
+            "shutdown Some(3) 4 true"
+        ]
+    );
+}
+
+#[test]
+fn test_id_filter() {
+    let mut probes = HashMap::new();
+    for (id, name) in [(0, "fifo_in"), (1, "fifo_out"), (2, "rule_add")] {
+        let info = ProbeInfo {
+            id,
+            name: name.to_string(),
+            put_width: 16,
+            get_width: 0,
+        };
+        probes.insert(id, info);
+    }
+    let filter = IdFilter::new().name("fifo_*").range(10..12).id(20);
+    let matched: Vec<u32> = (0..30).filter(|id| filter.matches(*id, &probes)).collect();
+    assert_eq!(matched, vec![0, 1, 10, 11, 20]);
+
+    let filter = IdFilter::new().name("?ule*");
+    assert!(filter.matches(2, &probes));
+    assert!(!filter.matches(1, &probes));
+    assert!(IdFilter::all().matches(u32::MAX, &probes));
+}
+
+#[test]
+fn test_subscription_handle() {
+    let mut publisher = B2RPublisher::new_with("/tmp/test_subscription_handle");
+
+    // record the cycles of the messages
+    struct Recorder {
+        cycles: Arc<Mutex<Vec<u32>>>,
+    }
+    impl Subscriber for Recorder {
+        fn update(&mut self, messages: Vec<B2RMessage>) -> Vec<R2BMessage> {
+            let mut cycles = self.cycles.lock().unwrap();
+            cycles.extend(messages.iter().map(|message| message.cycles));
+            Vec::new()
+        }
+    }
+    // resume the monitor when the trigger probe sends 1, remove it when sends 2
+    struct Trigger {
+        monitor: SubscriptionHandle,
+    }
+    impl Subscriber for Trigger {
+        fn update(&mut self, messages: Vec<B2RMessage>) -> Vec<R2BMessage> {
+            for message in messages {
+                match message.message[0] {
+                    1 => self.monitor.resume(),
+                    2 => self.monitor.remove(),
+                    _ => {}
+                }
+            }
+            Vec::new()
+        }
+
+        fn subscribed_ids(&self) -> Vec<u32> {
+            vec![0]
+        }
+    }
+
+    let monitor_cycles = Arc::new(Mutex::new(Vec::new()));
+    let monitor = publisher.add_paused_subscriber_with(
+        IdFilter::new().range(1..3),
+        Recorder {
+            cycles: monitor_cycles.clone(),
+        },
+    );
+    publisher.add_subscriber(Trigger {
+        monitor: monitor.clone(),
+    });
+
+    let sim = thread::spawn(|| {
+        thread::sleep(Duration::from_micros(500));
+        let mut stream = UnixStream::connect(String::from("/tmp/test_subscription_handle"))
+            .expect("Failed to connect to socket");
+        let triggers = [0, 0, 1, 0, 0, 2, 0];
+        for (cycle, trigger) in triggers.iter().enumerate() {
+            let cycle = cycle as u32;
+            put(0, cycle, vec![*trigger], &mut stream);
+            put(1, cycle, vec![0], &mut stream);
+            put(3, cycle, vec![0], &mut stream);
+            cycle_end(cycle, &mut stream);
+        }
+        put_shut_down(&mut stream);
+    });
+
+    publisher.serve();
+    sim.join().unwrap();
+    // resumed from cycle 3 and removed from cycle 6
+    assert_eq!(*monitor_cycles.lock().unwrap(), vec![3, 4, 5]);
+    assert!(monitor.is_removed());
+}
+
+#[test]
+fn test_parallel_subscriber() {
+    let mut publisher = B2RPublisher::new_with("/tmp/test_parallel_subscriber");
+    publisher.set_threads(2);
+
+    // put its tag to the probe 9 for every message, the slow one finishes last
+    struct Tagger {
+        tag: u8,
+        delay: Duration,
+        messages: Arc<Mutex<Vec<Arc<[B2RMessage]>>>>,
+    }
+    impl ParallelSubscriber for Tagger {
+        fn update(&mut self, messages: SharedMessages, context: &mut ParallelContext) {
+            thread::sleep(self.delay);
+            for message in messages.iter() {
+                assert_eq!(message.id, 0);
+                context.put(9, vec![self.tag]);
+            }
+            let mut shared = self.messages.lock().unwrap();
+            shared.push(messages.cycle_messages().clone());
+        }
+
+        fn subscribed_ids(&self) -> Vec<u32> {
+            vec![0]
+        }
+    }
+    let shared = Arc::new(Mutex::new(Vec::new()));
+    publisher.add_parallel_subscriber(Tagger {
+        tag: 1,
+        delay: Duration::from_millis(5),
+        messages: shared.clone(),
+    });
+    publisher.add_parallel_subscriber(Tagger {
+        tag: 2,
+        delay: Duration::ZERO,
+        messages: shared.clone(),
+    });
+
+    let sim = thread::spawn(|| {
+        thread::sleep(Duration::from_micros(500));
+        let mut stream = UnixStream::connect(String::from("/tmp/test_parallel_subscriber"))
+            .expect("Failed to connect to socket");
+        for cycle in 0..3 {
+            put(0, cycle, vec![0], &mut stream);
+            put(1, cycle, vec![0], &mut stream);
+            cycle_end(cycle, &mut stream);
+            // merged in the order of the subscribers
+            assert_eq!(get(9, cycle + 1, 1, &mut stream), vec![1]);
+            assert_eq!(get(9, cycle + 1, 1, &mut stream), vec![2]);
+        }
+        put_shut_down(&mut stream);
+    });
+
+    publisher.serve();
+    sim.join().unwrap();
+    let shared = shared.lock().unwrap();
+    assert_eq!(shared.len(), 6);
+    for pair in shared.chunks(2) {
+        assert!(Arc::ptr_eq(&pair[0], &pair[1]));
+        assert_eq!(pair[0].len(), 2);
+    }
+}
+
+#[test]
+fn test_parallel_subscriber_panic() {
+    let mut publisher = B2RPublisher::new_with("/tmp/test_parallel_subscriber_panic");
+    // the second update waits for the only worker
+    publisher.set_threads(1);
+
+    struct Failing;
+    impl ParallelSubscriber for Failing {
+        fn update(&mut self, _messages: SharedMessages, context: &mut ParallelContext) {
+            if context.cycle() == 1 {
+                panic!("the subscriber failed");
+            }
+        }
+    }
+    struct Passing;
+    impl ParallelSubscriber for Passing {
+        fn update(&mut self, _messages: SharedMessages, _context: &mut ParallelContext) {}
+    }
+    publisher.add_parallel_subscriber_with(IdFilter::all(), Failing);
+    publisher.add_parallel_subscriber_with(IdFilter::all(), Passing);
+
+    let _ = thread::spawn(|| {
+        thread::sleep(Duration::from_micros(500));
+        let mut stream = UnixStream::connect(String::from("/tmp/test_parallel_subscriber_panic"))
+            .expect("Failed to connect to socket");
+        for cycle in 0..3 {
+            put(0, cycle, vec![0], &mut stream);
+            cycle_end(cycle, &mut stream);
+        }
+        put_shut_down(&mut stream);
+    });
+
+    // the panic is propagated to serve() instead of blocking it
+    let panic =
+        std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| publisher.serve())).unwrap_err();
+    assert_eq!(panic.downcast_ref::<&str>(), Some(&"the subscriber failed"));
+}
+
+#[test]
+fn test_getter_fan_out() {
+    let mut server = B2RServer::new_with("/tmp/test_getter_fan_out");
+    let handle = server.serve();
+    thread::sleep(Duration::from_micros(400));
+    let mut stream = UnixStream::connect(String::from("/tmp/test_getter_fan_out"))
+        .expect("Failed to connect to socket");
+
+    // taken by the first getter
+    put(0, 0, vec![0], &mut stream);
+    let mut all_getter = IDGetter::new(&server);
+    assert_eq!(all_getter.get(0).message, vec![0]);
+
+    let mut id_getter = IDGetter::with_ids(&server, &[1]);
+    put(1, 1, vec![1], &mut stream);
+    put(0, 1, vec![2], &mut stream);
+    assert_eq!(all_getter.get(1).message, vec![1]);
+    assert_eq!(id_getter.get(1).message, vec![1]);
+    assert_eq!(all_getter.get(0).message, vec![2]);
+    assert!(id_getter.try_get(0).is_none());
+
+    // a dropped getter doesn't receive messages
+    drop(id_getter);
+    put(1, 2, vec![3], &mut stream);
+    assert_eq!(all_getter.get(1).cycles, 2);
+
+    put_shut_down(&mut stream);
+    handle.join().unwrap();
+    assert!(all_getter.try_get(1).is_none());
+}
+
+#[test]
+fn test_overflow_policy() {
+    let mut server = B2RServer::new_with("/tmp/test_overflow_policy");
+    server.set_capacity(0, 2, OverflowPolicy::DropOldest);
+    server.set_capacity(1, 2, OverflowPolicy::DropNewest);
+    server.set_capacity(2, 2, OverflowPolicy::SpillToDisk);
+    server.set_capacity(3, 1, OverflowPolicy::Block);
+    let mut id_getter = IDGetter::new(&server);
+    let handle = server.serve();
+    thread::sleep(Duration::from_micros(400));
+    let mut stream = UnixStream::connect(String::from("/tmp/test_overflow_policy"))
+        .expect("Failed to connect to socket");
+
+    for cycle in 0..5 {
+        for id in 0..3 {
+            put(id, cycle, vec![cycle as u8], &mut stream);
+        }
+    }
+    put(9, 5, vec![0], &mut stream);
+    id_getter.get(9);
+
+    let cycles = |messages: Vec<B2RMessage>| -> Vec<u32> {
+        messages.iter().map(|message| message.cycles).collect()
+    };
+    assert_eq!(cycles(id_getter.get_id_all(0)), vec![3, 4]);
+    assert_eq!(cycles(id_getter.get_id_all(1)), vec![0, 1]);
+    assert_eq!(cycles(id_getter.get_id_all(2)), vec![0, 1, 2, 3, 4]);
+    assert_eq!(server.dropped_messages(0), 3);
+    assert_eq!(server.dropped_messages(1), 3);
+    assert_eq!(server.dropped_messages(2), 0);
+
+    // the server waits for the getter to take the first message
+    put(3, 6, vec![0], &mut stream);
+    put(3, 7, vec![1], &mut stream);
+    put(9, 7, vec![0], &mut stream);
+    thread::sleep(Duration::from_millis(10));
+    assert!(id_getter.try_get(9).is_none());
+    assert_eq!(id_getter.get(3).cycles, 6);
+    assert_eq!(id_getter.get(9).cycles, 7);
+    assert_eq!(id_getter.get(3).cycles, 7);
+    assert_eq!(server.dropped_messages(3), 0);
+
+    put_shut_down(&mut stream);
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_disk_log() {
+    let mut server = B2RServer::new_with("/tmp/test_disk_log");
+    let mut log = DiskLog::create("/tmp/test_disk_log_store").unwrap();
+    log.set_segment_bytes(64);
+    server.set_store(log);
+    server.set_capacity(1, 2, OverflowPolicy::DropNewest);
+    let mut id_getter = IDGetter::new(&server);
+    let mut cycle_getter = CycleGetter::new(&server);
+    let handle = server.serve();
+    thread::sleep(Duration::from_micros(400));
+    let mut stream = UnixStream::connect(String::from("/tmp/test_disk_log"))
+        .expect("Failed to connect to socket");
+
+    for cycle in 0..4 {
+        put(0, cycle, vec![cycle as u8; 8], &mut stream);
+        put(1, cycle, vec![cycle as u8], &mut stream);
+    }
+    put_shut_down(&mut stream);
+    handle.join().unwrap();
+
+    // the newest messages from probe 1 are dropped by both getters
+    for cycle in 0..4 {
+        assert_eq!(id_getter.get(0).message, vec![cycle as u8; 8]);
+        let messages = cycle_getter.get_cycle_message();
+        assert_eq!(messages.len(), if cycle < 2 { 2 } else { 1 });
+    }
+    assert_eq!(id_getter.get_id_all(1).len(), 2);
+    assert_eq!(server.dropped_messages(1), 4);
+    drop(server);
+
+    let log = DiskLog::open("/tmp/test_disk_log_store").unwrap();
+    assert_eq!(log.count(0), 4);
+    assert_eq!(log.count(1), 4);
+    assert_eq!(log.cycle_range(0, 1..3), 1..3);
+    assert_eq!(log.cycle_range(1, 5..9), 4..4);
+    assert_eq!(log.get(1, 3).unwrap().message, vec![3]);
+    assert!(log.get(1, 4).is_none());
+
+    let query = MessageQuery::new(log);
+    assert_eq!(query.at_cycle(3).len(), 2);
+    assert_eq!(query.last_n(0, 1)[0].cycles, 3);
+}
+
+#[test]
+fn test_query() {
+    let mut server = B2RServer::new_with("/tmp/test_query");
+    assert!(server.query().is_none());
+    server.set_store(MemoryStore::new());
+    let query = server.query().unwrap();
+    let mut id_getter = IDGetter::new(&server);
+    let handle = server.serve();
+    thread::sleep(Duration::from_micros(400));
+    let mut stream =
+        UnixStream::connect(String::from("/tmp/test_query")).expect("Failed to connect to socket");
+
+    for cycle in 0..6 {
+        put(0, cycle, vec![cycle as u8 * 2], &mut stream);
+        if cycle % 2 == 0 {
+            put(1, cycle, vec![cycle as u8], &mut stream);
+        }
+    }
+    put_shut_down(&mut stream);
+    handle.join().unwrap();
+
+    let cycles = |messages: Vec<B2RMessage>| -> Vec<u32> {
+        messages.iter().map(|message| message.cycles).collect()
+    };
+    assert_eq!(cycles(query.messages(0, 2..5)), vec![2, 3, 4]);
+    assert_eq!(cycles(query.messages(1, 1..4)), vec![2]);
+    let at_cycle = query.at_cycle(4);
+    assert_eq!(at_cycle.len(), 2);
+    assert_eq!((at_cycle[0].id, at_cycle[1].id), (0, 1));
+    assert_eq!(cycles(query.last_n(1, 2)), vec![2, 4]);
+    assert_eq!(query.last_n(1, 10).len(), 3);
+    let first = query.find_first(0, |message| message.message[0] > 5);
+    assert_eq!(first.unwrap().cycles, 3);
+    assert!(query
+        .find_first(1, |message| message.message[0] > 5)
+        .is_none());
+
+    // the queries don't consume the messages
+    assert_eq!(id_getter.get_id_all(0).len(), 6);
+    assert_eq!(query.count(0), 6);
+}
+
+fn pipeline_state(
+    cycle: u32,
+    full_fifos: &[u32],
+    empty_fifos: &[u32],
+    fire_rules: &[u32],
+) -> PipeLineState {
+    let full = full_fifos.iter().map(|id| (*id, FifoStatus::Full));
+    let empty = empty_fifos.iter().map(|id| (*id, FifoStatus::Empty));
+    PipeLineState {
+        cycle: Some(cycle),
+        fifos: full.chain(empty).collect(),
+        fire_rules: fire_rules.to_vec(),
+        ..Default::default()
+    }
+}
+
+#[test]
+fn test_deadlock_detector() {
+    // the ten stage pipeline stuck at stage5 (rule 14)
+    let mut detector = DeadlockDetector::new(3);
+    detector.connect(10, &[], &[0]);
+    for i in 1..9 {
+        detector.connect(10 + i, &[i - 1], &[i]);
+    }
+    let reports = Arc::new(Mutex::new(Vec::new()));
+    let callback_reports = reports.clone();
+    detector.on_deadlock(move |report| callback_reports.lock().unwrap().push(report.since));
+
+    let state = |cycle: u32, fire_rules: Vec<u32>| {
+        pipeline_state(cycle, &[0, 1, 2, 3], &[4, 5, 6, 7, 8], &fire_rules)
+    };
+    assert!(detector.update(&state(0, vec![10])).is_none());
+    assert!(detector.update(&state(1, vec![])).is_none());
+    assert!(detector.update(&state(2, vec![])).is_none());
+    let report = detector.update(&state(3, vec![])).unwrap();
+    assert!(detector.deadlocked());
+    assert!(detector.update(&state(4, vec![])).is_none());
+
+    assert_eq!((report.since, report.cycle), (1, 3));
+    assert_eq!(report.blocking_rule, Some(14));
+    let chain: Vec<u32> = report
+        .chain
+        .iter()
+        .map(|diagnosis| diagnosis.rule)
+        .collect();
+    assert_eq!(chain, vec![10, 11, 12, 13, 14]);
+    assert_eq!(report.chain[0].status, RuleStatus::Blocked(0));
+    assert_eq!(report.rules[5].status, RuleStatus::Starved(4));
+    assert!(report.to_string().contains("rule 14 cannot fire"));
+    assert_eq!(*reports.lock().unwrap(), vec![1]);
+    assert_eq!(detector.report().unwrap().cycle, 3);
+
+    // the pipeline makes progress again
+    assert!(detector.update(&state(5, vec![14])).is_none());
+    assert!(!detector.deadlocked());
+}
+
+#[test]
+fn test_stall_attribution() {
+    let topology = PipelineTopology::new()
+        .stage("fetch", 10, &[], &[0])
+        .stage("decode", 11, &[0], &[1])
+        .stage("execute", 12, &[1], &[]);
+    assert_eq!(topology.fifos(), vec![0, 1]);
+    assert_eq!(topology.reader(0).unwrap().name, "decode");
+    assert_eq!(topology.writer(1).unwrap().rule, 11);
+    let mut detector = DeadlockDetector::with_topology(2, topology.clone());
+    let mut stalls = StallAttribution::new(topology);
+
+    let state = |full_fifos: Vec<u32>, empty_fifos: Vec<u32>, fire_rules: Vec<u32>| {
+        pipeline_state(0, &full_fifos, &empty_fifos, &fire_rules)
+    };
+    let statuses = stalls.update(&state(vec![], vec![0, 1], vec![10]));
+    assert_eq!(
+        statuses,
+        vec![
+            StageStatus::Fired,
+            StageStatus::Starved(0),
+            StageStatus::Starved(1)
+        ]
+    );
+    stalls.update(&state(vec![], vec![1], vec![10, 11]));
+    for _ in 0..2 {
+        let state = state(vec![0], vec![1], vec![]);
+        stalls.update(&state);
+        detector.update(&state);
+    }
+
+    let fetch = stalls.histogram("fetch").unwrap();
+    assert_eq!((fetch.fired, fetch.blocked), (2, 2));
+    assert_eq!(fetch.blocked_by[&0], 2);
+    let decode = stalls.histogram("decode").unwrap();
+    assert_eq!((decode.fired, decode.starved, decode.idle), (1, 1, 2));
+    let execute = stalls.histogram("execute").unwrap();
+    assert_eq!((execute.starved, execute.cycles()), (4, 4));
+    assert!(stalls.to_string().contains("decode"));
+    assert_eq!(detector.report().unwrap().blocking_rule, Some(11));
+
+    let server = B2RServer::new_with("/tmp/test_stall_attribution");
+    let mut pipe_getter = PipeLineGetter::new(&server);
+    stalls.topology().add_probes_to(&mut pipe_getter);
+    assert_eq!(pipe_getter.fifo_probes(), &[0, 1]);
+    assert_eq!(pipe_getter.rule_probes(), &[10, 11, 12]);
+}
+
+#[test]
+fn test_pipeline_stats() {
+    let mut stats = PipelineStats::new(&[0, 1], &[10, 11]);
+    let state = |cycle: u32, full_fifos: Vec<u32>, empty_fifos: Vec<u32>, fire_rules: Vec<u32>| {
+        pipeline_state(cycle, &full_fifos, &empty_fifos, &fire_rules)
+    };
+    stats.update(&state(0, vec![], vec![0, 1], vec![10]));
+    stats.update(&state(1, vec![0], vec![1], vec![10]));
+    stats.update(&state(2, vec![0], vec![1], vec![11]));
+    stats.update(&state(3, vec![], vec![0], vec![10, 11]));
+
+    let summary = stats.summary();
+    assert_eq!(summary.cycles, 4);
+    assert_eq!(
+        (summary.first_cycle, summary.last_cycle),
+        (Some(0), Some(3))
+    );
+    assert_eq!(summary.rules[0].fired, 3);
+    assert_eq!(summary.rules[1].fire_rate, 0.5);
+    let fifo_0 = &summary.fifos[0];
+    assert_eq!((fifo_0.full_cycles, fifo_0.empty_cycles), (2, 2));
+    assert_eq!(fifo_0.full_runs[&2], 1);
+    assert_eq!(fifo_0.empty_runs[&1], 2);
+    // the run of fifo 1 is still open
+    let fifo_1 = &summary.fifos[1];
+    assert_eq!((fifo_1.empty_fraction, fifo_1.longest_stall), (0.75, 3));
+    assert!(summary.to_string().contains("fire rate"));
+    let json: serde_json::Value = serde_json::from_str(&summary.to_json()).unwrap();
+    assert_eq!(json["fifos"][1]["empty_runs"]["3"], 1);
+
+    // as a subscriber
+    let mut publisher = B2RPublisher::new_with("/tmp/test_pipeline_stats");
+    let mut stats = PipelineStats::new(&[0], &[10]);
+    stats.set_print_on_shutdown(false);
+    stats.set_json_path("/tmp/test_pipeline_stats.json");
+    publisher.add_subscriber(stats);
+
+    let _ = thread::spawn(|| {
+        thread::sleep(Duration::from_micros(500));
+        let mut stream = UnixStream::connect(String::from("/tmp/test_pipeline_stats"))
+            .expect("Failed to connect to socket");
+        put(0, 0, vec![1, 0], &mut stream);
+        put(10, 0, vec![1], &mut stream);
+        put(0, 1, vec![0, 1], &mut stream);
+        put(0, 2, vec![0, 1], &mut stream);
+        put(10, 2, vec![1], &mut stream);
+        put_shut_down(&mut stream);
+    });
+
+    publisher.serve();
+    let json = std::fs::read_to_string("/tmp/test_pipeline_stats.json").unwrap();
+    let json: serde_json::Value = serde_json::from_str(&json).unwrap();
+    assert_eq!(json["cycles"], 3);
+    assert_eq!(json["rules"][0]["fired"], 2);
+    assert_eq!(json["fifos"][0]["full_runs"]["2"], 1);
+    assert_eq!(json["fifos"][0]["empty_cycles"], 1);
+
+    // an unwritable summary doesn't panic the publisher
+    let mut stats = PipelineStats::new(&[0], &[10]);
+    stats.set_json_path("/nonexistent/test_pipeline_stats.json");
+    let summary = Summary {
+        cycles: None,
+        messages: 0,
+        stopped: false,
+    };
+    Subscriber::on_shutdown(&mut stats, &summary);
+}
+
+#[test]
+fn test_fifo_occupancy() {
+    let mut server = B2RServer::new_with("/tmp/test_fifo_occupancy");
+    let mut pipe_getter = PipeLineGetter::new(&server);
+    pipe_getter.add_fifo_probe(0);
+    pipe_getter.add_fifo_probe(1);
+    let _ = server.serve();
+    thread::sleep(Duration::from_micros(400));
+    let mut stream = UnixStream::connect(String::from("/tmp/test_fifo_occupancy"))
+        .expect("Failed to connect to socket");
+
+    // notFull, notEmpty, enq, deq, count
+    let occupancy = |not_full: u8, not_empty: u8, enq: u8, deq: u8, count: u32| {
+        [
+            vec![not_full, not_empty, enq, deq],
+            count.to_le_bytes().to_vec(),
+        ]
+        .concat()
+    };
+    // fifo 0 of depth 2: enq at 0 and 1, deq at 2 and 4
+    let fifo_0 = [
+        occupancy(1, 0, 1, 0, 0),
+        occupancy(1, 1, 1, 0, 1),
+        occupancy(0, 1, 0, 1, 2),
+        occupancy(1, 1, 0, 0, 1),
+        occupancy(1, 1, 0, 1, 1),
+    ];
+    for (cycle, message) in fifo_0.into_iter().enumerate() {
+        put(0, cycle as u32, message, &mut stream);
+        // fifo 1 is never drained, the first state of it has 1 element
+        let enq = (cycle % 2) as u8;
+        put(1, cycle as u32, occupancy(1, 1, enq, 0, 1), &mut stream);
+    }
+    assert_eq!(cycle_end(4, &mut stream), CYCLE_END_ACK);
+
+    thread::sleep(Duration::from_micros(400));
+    let mut stats = PipelineStats::new(&[0, 1], &[]);
+    for cycle in 0..5 {
+        let state = pipe_getter.get_pipeline_state();
+        assert_eq!(state.cycle, Some(cycle));
+        stats.update(&state);
+        if cycle == 1 {
+            // neither full nor empty
+            assert_eq!(state.status(0), FifoStatus::Partial);
+            assert_eq!(
+                state.occupancy(0),
+                Some(&FifoOccupancy {
+                    id: 0,
+                    count: 1,
+                    enq: true,
+                    deq: false
+                })
+            );
+        }
+    }
+
+    let summary = stats.summary();
+    let fifo_0 = &summary.fifos[0];
+    assert_eq!((fifo_0.enqs, fifo_0.deqs, fifo_0.max_count), (2, 2, 2));
+    assert_eq!(fifo_0.mean_count, Some(1.0));
+    // latencies 2 and 3
+    assert_eq!((fifo_0.max_latency, fifo_0.mean_latency), (3, Some(2.5)));
+    let fifo_1 = &summary.fifos[1];
+    assert_eq!((fifo_1.enqs, fifo_1.mean_latency), (2, None));
+    assert_eq!(summary.never_drained(), vec![1]);
+    assert!(summary.to_string().contains("fifo 1 is never drained"));
+}
+
+#[test]
+fn test_transaction_monitor() {
+    // requests on probe 1 and responses on probe 2, the first byte is the tag
+    let message = |id: u32, cycles: u32, tag: u8, data: u8| B2RMessage {
+        id,
+        cycles,
+        message: vec![tag, data],
+    };
+    let mut monitor = TransactionMonitor::new(1, 2, |payload: &[u8]| payload[0]);
+    assert!(monitor.record(&message(1, 0, 7, 10)).is_none());
+    assert!(monitor.record(&message(1, 1, 8, 11)).is_none());
+    assert!(monitor.record(&message(1, 2, 7, 12)).is_none());
+    assert!(monitor.record(&message(3, 2, 7, 12)).is_none());
+    assert_eq!(monitor.outstanding(), vec![(&7, 0), (&8, 1), (&7, 2)]);
+    let transaction = monitor.record(&message(2, 4, 7, 20)).unwrap();
+    assert_eq!(
+        transaction,
+        Transaction {
+            key: 7,
+            start_cycle: 0,
+            end_cycle: 4,
+            latency: 4,
+            payloads: (vec![7, 10], vec![7, 20]),
+        }
+    );
+    assert_eq!(
+        monitor.record(&message(2, 5, 7, 21)).unwrap().start_cycle,
+        2
+    );
+    assert!(monitor.record(&message(2, 6, 9, 22)).is_none());
+    assert_eq!(monitor.outstanding(), vec![(&8, 1)]);
+    assert_eq!((monitor.completed(), monitor.unmatched_ends()), (2, 1));
+    assert_eq!(monitor.max_outstanding(), 3);
+    assert_eq!(monitor.latency_histogram()[&3], 1);
+    assert_eq!(
+        (monitor.mean_latency(), monitor.max_latency()),
+        (Some(3.5), Some(4))
+    );
+    assert!(monitor.to_string().contains("outstanding 8 since cycle 1"));
+    // a transaction starts and ends in one cycle
+    Subscriber::update(&mut monitor, vec![message(2, 7, 5, 0), message(1, 7, 5, 0)]);
+    assert_eq!(monitor.latency_histogram()[&0], 1);
+
+    // a single probe, the second byte tells the start or the end
+    let mut publisher = B2RPublisher::new_with("/tmp/test_transaction_monitor");
+    let mut monitor = TransactionMonitor::single_probe(5, |payload: &[u8]| {
+        let event = match payload[1] {
+            0 => TransactionEvent::Start,
+            _ => TransactionEvent::End,
+        };
+        Some((event, payload[0]))
+    });
+    monitor.set_print_on_shutdown(false);
+    let transactions = Arc::new(Mutex::new(Vec::new()));
+    let callback_transactions = transactions.clone();
+    monitor.on_transaction(move |transaction| {
+        callback_transactions
+            .lock()
+            .unwrap()
+            .push(transaction.clone())
+    });
+    publisher.add_subscriber(monitor);
+
+    let _ = thread::spawn(|| {
+        thread::sleep(Duration::from_micros(500));
+        let mut stream = UnixStream::connect(String::from("/tmp/test_transaction_monitor"))
+            .expect("Failed to connect to socket");
+        put(5, 0, vec![1, 0], &mut stream);
+        put(5, 2, vec![2, 0], &mut stream);
+        put(5, 3, vec![2, 1], &mut stream);
+        put(5, 4, vec![1, 1], &mut stream);
+        put_shut_down(&mut stream);
+    });
+
+    publisher.serve();
+    let transactions = transactions.lock().unwrap();
+    assert_eq!(transactions.len(), 2);
+    assert_eq!((transactions[0].key, transactions[0].latency), (2, 1));
+    assert_eq!((transactions[1].key, transactions[1].latency), (1, 4));
+}
+
+#[test]
+fn test_scoreboard() {
+    let message = |id: u32, cycles: u32, data: u32| B2RMessage {
+        id,
+        cycles,
+        message: data.to_le_bytes().to_vec(),
+    };
+    // in order
+    let mut scoreboard = Scoreboard::new(1, 2, |x: u32| x + 1);
+    scoreboard.record(&message(1, 0, 10));
+    scoreboard.record(&message(1, 1, 20));
+    assert!(scoreboard.record(&message(2, 3, 11)).is_none());
+    let mismatch = scoreboard.record(&message(2, 4, 22)).unwrap();
+    assert_eq!(
+        mismatch,
+        Mismatch {
+            input_cycle: Some(1),
+            output_cycle: 4,
+            expected: Some(21),
+            actual: 22
+        }
+    );
+    assert_eq!(
+        mismatch.to_string(),
+        "cycle 4: expected 21 for the input of cycle 1, got 22"
+    );
+    let unexpected = scoreboard.record(&message(2, 5, 30)).unwrap();
+    assert_eq!((unexpected.expected, unexpected.input_cycle), (None, None));
+    assert_eq!(
+        (scoreboard.matched(), scoreboard.mismatches().len()),
+        (1, 2)
+    );
+    assert!(!scoreboard.passed());
+
+    // out of order by the tag in the high byte, the inputs put by Rust
+    let mut scoreboard: Scoreboard<u32, u32> =
+        Scoreboard::with_inputs(2, [0x0100_0001, 0x0200_0002], |x: u32| x ^ 0xff);
+    scoreboard.out_of_order(|input| (input >> 24) as u64, |output| (output >> 24) as u64);
+    scoreboard.push_input(None, 0x0100_0003);
+    assert!(scoreboard.record(&message(2, 1, 0x0200_00fd)).is_none());
+    assert!(scoreboard.record(&message(2, 2, 0x0100_00fe)).is_none());
+    assert_eq!(scoreboard.pending(), 1);
+    assert!(scoreboard.record(&message(2, 3, 0x0100_00fc)).is_none());
+    assert!(scoreboard.passed());
+    assert!(scoreboard
+        .to_string()
+        .contains("3 matched, 0 mismatched, 0 pending"));
+
+    // stop Bluesim on the first mismatch
+    let mut publisher = B2RPublisher::new_with("/tmp/test_scoreboard");
+    let mut scoreboard = Scoreboard::new(1, 2, |x: u8| x.wrapping_mul(2));
+    scoreboard.set_stop_on_mismatch(true);
+    scoreboard.set_print_on_shutdown(false);
+    publisher.add_subscriber(scoreboard);
+
+    let sim = thread::spawn(|| {
+        thread::sleep(Duration::from_micros(500));
+        let mut stream = UnixStream::connect(String::from("/tmp/test_scoreboard"))
+            .expect("Failed to connect to socket");
+        let mut cycle = 0;
+        loop {
+            put(1, cycle, vec![cycle as u8], &mut stream);
+            // the output of cycle 3 is wrong
+            if cycle >= 1 {
+                let output = (cycle as u8 - 1) * 2 + (cycle == 3) as u8;
+                put(2, cycle, vec![output], &mut stream);
+            }
+            if cycle_end(cycle, &mut stream) == CYCLE_END_STOP {
+                break;
+            }
+            cycle += 1;
+        }
+        put_shut_down(&mut stream);
+        cycle
+    });
+
+    publisher.serve();
+    assert_eq!(sim.join().unwrap(), 3);
+}
+
+#[test]
+fn test_coverage() {
+    let new_coverage = || {
+        let mut coverage = Coverage::new();
+        // the opcode in the low 4 bits of probe 1
+        coverage.add_point(
+            CoverPoint::new("opcode", 1)
+                .decode(|payload| (payload[0] & 0xf) as u64)
+                .values("load", &[1])
+                .values("store", &[2])
+                .transition("load_store", &[1, 2]),
+        );
+        coverage.add_point(
+            CoverPoint::new("size", 2)
+                .range("small", 0..=15)
+                .range("large", 16..=255),
+        );
+        coverage.add_cross("opcode_size", "opcode", "size");
+        coverage
+    };
+    let message = |id: u32, cycles: u32, data: u8| B2RMessage {
+        id,
+        cycles,
+        message: vec![data],
+    };
+
+    let mut coverage = new_coverage();
+    assert_eq!(coverage.ids(), vec![1, 2]);
+    coverage.sample(&[message(1, 0, 0x11), message(2, 0, 4)]);
+    coverage.sample(&[message(1, 1, 0x2)]);
+    coverage.sample(&[message(2, 2, 100)]);
+    let database = coverage.database();
+    assert_eq!(database.points["opcode"]["load"], 1);
+    assert_eq!(database.points["opcode"]["load_store"], 1);
+    assert_eq!(database.points["size"]["large"], 1);
+    assert_eq!(database.points["opcode_size"]["load x small"], 1);
+    assert_eq!(database.points["opcode_size"]["store x small"], 0);
+    assert_eq!(database.covered(Some("opcode")), (3, 3));
+    assert_eq!(database.covered(None), (6, 11));
+    assert!(database.holes().contains(&("opcode_size", "store x large")));
+    assert!(database.to_string().contains("opcode_size 16.7% (1/6)"));
+
+    // the second run as a subscriber
+    let mut publisher = B2RPublisher::new_with("/tmp/test_coverage");
+    let mut coverage = new_coverage();
+    coverage.set_print_on_shutdown(false);
+    coverage.set_json_path("/tmp/test_coverage.json");
+    publisher.add_subscriber(coverage);
+
+    let _ = thread::spawn(|| {
+        thread::sleep(Duration::from_micros(500));
+        let mut stream = UnixStream::connect(String::from("/tmp/test_coverage"))
+            .expect("Failed to connect to socket");
+        put(1, 0, vec![0x2], &mut stream);
+        put(2, 0, vec![200], &mut stream);
+        put_shut_down(&mut stream);
+    });
+
+    publisher.serve();
+    database.save("/tmp/test_coverage_0.json").unwrap();
+    let merged =
+        CoverageDb::merge_files(["/tmp/test_coverage_0.json", "/tmp/test_coverage.json"]).unwrap();
+    assert_eq!(merged.runs, 2);
+    assert_eq!(merged.points["opcode"]["store"], 2);
+    assert_eq!(merged.points["opcode_size"]["store x large"], 1);
+    assert_eq!(merged.covered(None), (7, 11));
+    assert_eq!(CoverageDb::from_json(&merged.to_json()).unwrap(), merged);
+
+    // an unwritable database doesn't panic the publisher
+    let mut coverage = new_coverage();
+    coverage.set_json_path("/nonexistent/test_coverage.json");
+    let summary = Summary {
+        cycles: None,
+        messages: 0,
+        stopped: false,
+    };
+    Subscriber::on_shutdown(&mut coverage, &summary);
+}
+
+#[test]
+fn test_assertions() {
+    let message = |id: u32, cycles: u32, data: Vec<u8>| B2RMessage {
+        id,
+        cycles,
+        message: data,
+    };
+    let new_checker = || {
+        let mut checker = AssertionChecker::new();
+        // whenever probe 3 fires with valid = 1, probe 7 fires within 2 cycles
+        checker.add(
+            "request_response",
+            Property::implies_within(Cond::value(3, |valid: u8| valid == 1), Cond::fired(7), 2),
+        );
+        // fifo 4 is never full for more than 2 cycles
+        checker.add("fifo_4", Property::at_most(Cond::fifo_full(4), 2));
+        checker.add(
+            "no_error",
+            Property::never(Cond::value(9, |error: u8| error != 0)),
+        );
+        checker.add(
+            "ack",
+            Property::implies_after(Cond::fired(5), Cond::fired(6), 1),
+        );
+        checker.set_history(2);
+        checker
+    };
+    let trace = vec![
+        message(3, 0, vec![1]),
+        message(4, 0, vec![0, 1]),
+        message(4, 1, vec![0, 1]),
+        message(7, 1, vec![0]),
+        message(3, 2, vec![1]),
+        message(4, 2, vec![0, 1]),
+        message(5, 2, vec![0]),
+        message(3, 3, vec![0]),
+        message(4, 3, vec![1, 1]),
+        message(6, 3, vec![0]),
+        message(4, 6, vec![1, 1]),
+        message(9, 6, vec![1]),
+        message(5, 7, vec![0]),
+        message(3, 8, vec![1]),
+    ];
+
+    let mut checker = new_checker();
+    assert_eq!(checker.ids(), vec![3, 4, 5, 6, 7, 9]);
+    let violations = checker.check_trace(trace.clone());
+    let failures: Vec<(&str, u32)> = violations
+        .iter()
+        .map(|violation| (violation.assertion.as_str(), violation.cycle))
+        .collect();
+    assert_eq!(
+        failures,
+        vec![
+            ("fifo_4", 2),
+            ("request_response", 4),
+            ("no_error", 6),
+            ("ack", 8),
+            ("request_response", 10),
+        ]
+    );
+    // the history of the 2 cycles when the response is missed
+    let history: Vec<u32> = violations[1]
+        .history
+        .iter()
+        .map(|message| message.cycles)
+        .collect();
+    assert_eq!(history, vec![3, 3, 3, 6, 6]);
+    assert!(violations[0]
+        .to_string()
+        .contains("assertion fifo_4 failed at cycle 2"));
+
+    // offline over the stored messages
+    let mut store = MemoryStore::new();
+    for message in &trace {
+        store.append(message);
+    }
+    let query = MessageQuery::new(store);
+    assert_eq!(new_checker().check_query(&query, 0..7).len(), 3);
+
+    // live, stop Bluesim on the first violation
+    let mut publisher = B2RPublisher::new_with("/tmp/test_assertions");
+    let mut checker = new_checker();
+    checker.set_stop_on_violation(true);
+    checker.set_print_on_shutdown(false);
+    publisher.add_subscriber(checker);
+
+    let sim = thread::spawn(move || {
+        thread::sleep(Duration::from_micros(500));
+        let mut stream = UnixStream::connect(String::from("/tmp/test_assertions"))
+            .expect("Failed to connect to socket");
+        let mut last_cycle = 0;
+        for message in trace {
+            if message.cycles != last_cycle {
+                if cycle_end(last_cycle, &mut stream) == CYCLE_END_STOP {
+                    break;
+                }
+                last_cycle = message.cycles;
+            }
+            put(message.id, message.cycles, message.message, &mut stream);
+        }
+        put_shut_down(&mut stream);
+        last_cycle
+    });
+
+    publisher.serve();
+    assert_eq!(sim.join().unwrap(), 2);
+}
+
+#[test]
+fn test_assertions_sampling() {
+    // probe 3 fires in every cycle with messages from probe 3, probe 8 is unrelated
+    let trace: Vec<B2RMessage> = [(3, 0), (3, 1), (8, 1), (8, 2), (3, 3)]
+        .into_iter()
+        .map(|(id, cycles)| B2RMessage {
+            id,
+            cycles,
+            message: vec![0],
+        })
+        .collect();
+    let new_checker = || {
+        let mut checker = AssertionChecker::new();
+        checker.add("fired_3", Property::always(Cond::fired(3)));
+        checker
+    };
+
+    // offline over the stored messages of all the probes
+    let mut store = MemoryStore::new();
+    for message in &trace {
+        store.append(message);
+    }
+    let query = MessageQuery::new(store);
+    assert!(new_checker().check_query(&query, 0..4).is_empty());
+    assert!(new_checker().check_trace(trace.clone()).is_empty());
+
+    // live, the cycle 2 with only probe 8 is not sampled either
+    let mut publisher = B2RPublisher::new_with("/tmp/test_assertions_sampling");
+    let mut checker = new_checker();
+    checker.set_stop_on_violation(true);
+    publisher.add_subscriber_with(IdFilter::all(), checker);
+
+    let sim = thread::spawn(move || {
+        thread::sleep(Duration::from_micros(500));
+        let mut stream = UnixStream::connect(String::from("/tmp/test_assertions_sampling"))
+            .expect("Failed to connect to socket");
+        let mut stopped = false;
+        let mut last_cycle = 0;
+        for message in trace {
+            if message.cycles != last_cycle {
+                stopped |= cycle_end(last_cycle, &mut stream) == CYCLE_END_STOP;
+                last_cycle = message.cycles;
+            }
+            put(message.id, message.cycles, message.message, &mut stream);
+        }
+        stopped |= cycle_end(last_cycle, &mut stream) == CYCLE_END_STOP;
+        put_shut_down(&mut stream);
+        stopped
+    });
+
+    publisher.serve();
+    assert!(!sim.join().unwrap());
+}
+
+#[test]
+fn test_logic_analyzer() {
+    // probe 1 counts the cycles, probe 2 fires at cycles 5, 12 and 14,
+    // probe 3 is valid at cycles 8 and 13
+    let mut trace = Vec::new();
+    for cycle in 0..20u32 {
+        trace.push(B2RMessage {
+            id: 1,
+            cycles: cycle,
+            message: vec![cycle as u8],
+        });
+        if [5, 12, 14].contains(&cycle) {
+            trace.push(B2RMessage {
+                id: 2,
+                cycles: cycle,
+                message: vec![1],
+            });
+        }
+        trace.push(B2RMessage {
+            id: 3,
+            cycles: cycle,
+            message: vec![[8, 13].contains(&cycle) as u8],
+        });
+    }
+    let new_analyzer = || {
+        let mut analyzer = LogicAnalyzer::new(2, 3);
+        // probe 2 fires, then probe 3 is valid in the next cycle
+        analyzer.trigger(Cond::fired(2));
+        analyzer.trigger_within(Cond::value(3, |valid: u8| valid == 1), 1);
+        analyzer.set_capture_ids(&[1, 2]);
+        analyzer.add_probe(ProbeInfo {
+            id: 1,
+            name: "counter".to_string(),
+            put_width: 8,
+            get_width: 0,
+        });
+        analyzer
+    };
+    let sample = |analyzer: &mut LogicAnalyzer, messages: &[B2RMessage]| {
+        let mut completed = None;
+        for cycle in 0..20u32 {
+            let cycle_messages: Vec<B2RMessage> = messages
+                .iter()
+                .filter(|message| message.cycles == cycle)
+                .cloned()
+                .collect();
+            if !cycle_messages.is_empty() && analyzer.sample(cycle, &cycle_messages) {
+                completed = Some(cycle);
+            }
+        }
+        completed
+    };
+
+    let mut analyzer = new_analyzer();
+    assert_eq!(analyzer.ids(), vec![1, 2, 3]);
+    assert_eq!(sample(&mut analyzer, &trace), Some(16));
+    let capture = analyzer.capture().unwrap().clone();
+    assert_eq!(capture.trigger_cycle, 13);
+    assert!(capture.complete);
+    assert_eq!(
+        (capture.first_cycle(), capture.last_cycle()),
+        (Some(11), Some(16))
+    );
+    assert_eq!(capture.ids(), vec![1, 2]);
+    assert_eq!(capture.messages.len(), 8);
+    assert!(capture.to_string().contains("    cycle 14 probe 2: [1]"));
+
+    let vcd = capture.to_vcd();
+    assert!(vcd.contains("$var wire 8 \" counter $end\n$var wire 1 # counter_valid $end\n"));
+    assert!(vcd.contains("$var wire 8 $ probe_2 $end\n"));
+    assert!(vcd.contains("#11\n$dumpvars\n0!\nb1011 \"\n1#\nbx $\n0%\n$end\n"));
+    assert!(vcd.contains("#13\n1!\nb1101 \"\n0%\n#14\n0!\nb1110 \"\nb1 $\n1%\n"));
+    assert!(vcd.ends_with("#17\n0#\n"));
+
+    // the simulation ends before the post-trigger window is full
+    let mut analyzer = new_analyzer();
+    let truncated: Vec<B2RMessage> = trace
+        .iter()
+        .filter(|message| message.cycles <= 14)
+        .cloned()
+        .collect();
+    assert_eq!(sample(&mut analyzer, &truncated), None);
+    let capture = analyzer.finish().unwrap();
+    assert!(!capture.complete);
+    assert_eq!(capture.last_cycle(), Some(14));
+
+    let send_trace = |path: &'static str, trace: Vec<B2RMessage>| {
+        thread::spawn(move || {
+            thread::sleep(Duration::from_micros(500));
+            let mut stream = UnixStream::connect(path).expect("Failed to connect to socket");
+            let mut last_cycle = 0;
+            for message in trace {
+                if message.cycles != last_cycle {
+                    if cycle_end(last_cycle, &mut stream) == CYCLE_END_STOP {
+                        break;
+                    }
+                    last_cycle = message.cycles;
+                }
+                put(message.id, message.cycles, message.message, &mut stream);
+            }
+            put_shut_down(&mut stream);
+            last_cycle
+        })
+    };
+
+    // live, stop Bluesim when the capture completes
+    let mut publisher = B2RPublisher::new_with("/tmp/test_logic_analyzer");
+    let mut analyzer = new_analyzer();
+    let captured = Arc::new(Mutex::new(None));
+    let captured_clone = captured.clone();
+    analyzer.on_capture(move |capture| {
+        *captured_clone.lock().unwrap() = Some(capture.clone());
+    });
+    analyzer.set_vcd_path("/tmp/test_logic_analyzer.vcd");
+    analyzer.set_stop_on_capture(true);
+    publisher.add_subscriber(analyzer);
+    let sim = send_trace("/tmp/test_logic_analyzer", trace.clone());
+    publisher.serve();
+    assert_eq!(sim.join().unwrap(), 16);
+    let capture = captured.lock().unwrap().take().unwrap();
+    assert_eq!(capture.messages.len(), 8);
+    assert_eq!(
+        std::fs::read_to_string("/tmp/test_logic_analyzer.vcd").unwrap(),
+        vcd
+    );
+
+    // over a B2RServer, capturing all the probes
+    let mut server = B2RServer::new_with("/tmp/test_logic_analyzer_server");
+    let mut analyzer = LogicAnalyzer::new(0, 0);
+    analyzer.trigger(Cond::fired(2));
+    analyzer.trigger(Cond::fired(2));
+    analyzer.set_stop_on_capture(true);
+    // an unwritable VCD doesn't panic
+    analyzer.set_vcd_path("/nonexistent/test_logic_analyzer.vcd");
+    analyzer.attach(&server);
+    server.serve();
+    let sim = send_trace("/tmp/test_logic_analyzer_server", trace);
+    let capture = analyzer.run(&mut server).unwrap();
+    // the getter isn't synchronous, Bluesim may run a few more cycles
+    assert!(sim.join().unwrap() < 19);
+    assert_eq!(capture.trigger_cycle, 12);
+    assert_eq!(capture.ids(), vec![1, 2, 3]);
+}
+
+#[cfg(feature = "async")]
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_async_server() {
+    use tokio_stream::StreamExt;
+
+    let server = AsyncB2RServer::new_with("/tmp/test_async_server");
+    let handle = server.serve().unwrap();
+    let probe_stream = server.probe_stream(1);
+    let mut cycle_stream = server.cycle_stream();
+    server.put(5, vec![7]).await;
+
+    let sim = thread::spawn(|| {
+        let mut stream = UnixStream::connect(String::from("/tmp/test_async_server"))
+            .expect("Failed to connect to socket");
+        assert_eq!(get(5, 0, 1, &mut stream), vec![7]);
+        put(0, 0, vec![0], &mut stream);
+        put(1, 0, vec![1], &mut stream);
+        assert_eq!(cycle_end(0, &mut stream), CYCLE_END_ACK);
+        put(1, 1, vec![2], &mut stream);
+        assert_eq!(try_get(5, 1, 1, &mut stream), None);
+        put_shut_down(&mut stream);
+    });
+
+    let cycle_msgs = cycle_stream.next().await.unwrap();
+    assert_eq!(cycle_msgs.len(), 2);
+    assert!(cycle_msgs.iter().all(|msg| msg.cycles == 0));
+    let cycle_msgs = cycle_stream.next().await.unwrap();
+    assert_eq!(cycle_msgs[0].cycles, 1);
+    assert!(cycle_stream.next().await.is_none());
+
+    let probe_msgs: Vec<B2RMessage> = probe_stream.collect().await;
+    assert_eq!(probe_msgs.len(), 2);
+    // the streamed messages are not kept for get()
+    assert!(server.get(0).await.is_none());
+    assert!(server.get(1).await.is_none());
+
+    handle.await.unwrap().unwrap();
+    sim.join().unwrap();
+}
+
+#[cfg(feature = "async")]
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_async_capacity() {
+    let server = AsyncB2RServer::new_with("/tmp/test_async_capacity");
+    server.set_default_capacity(2, OverflowPolicy::DropOldest);
+    let handle = server.serve().unwrap();
+
+    let sim = thread::spawn(|| {
+        let mut stream = UnixStream::connect(String::from("/tmp/test_async_capacity"))
+            .expect("Failed to connect to socket");
+        for cycle in 0..5 {
+            put(0, cycle, vec![cycle as u8], &mut stream);
+        }
+        assert_eq!(cycle_end(4, &mut stream), CYCLE_END_ACK);
+        put_shut_down(&mut stream);
+    });
+
+    handle.await.unwrap().unwrap();
+    sim.join().unwrap();
+    assert_eq!(server.current_cycle(), 4);
+    // only the latest 2 messages are kept
+    assert_eq!(server.dropped_messages(0), 3);
+    assert_eq!(server.get(0).await.unwrap().cycles, 3);
+    assert_eq!(server.get(0).await.unwrap().cycles, 4);
+    assert!(server.get(0).await.is_none());
+}
+
+#[cfg(feature = "async")]
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_async_stream_capacity() {
+    use tokio_stream::StreamExt;
+
+    let server = AsyncB2RServer::new_with("/tmp/test_async_stream_capacity");
+    server.set_capacity(0, 2, OverflowPolicy::DropOldest);
+    let handle = server.serve().unwrap();
+    let probe_stream = server.probe_stream(0);
+
+    let sim = thread::spawn(|| {
+        let mut stream = UnixStream::connect(String::from("/tmp/test_async_stream_capacity"))
+            .expect("Failed to connect to socket");
+        for cycle in 0..5 {
+            put(0, cycle, vec![cycle as u8], &mut stream);
+        }
+        put(1, 4, vec![0], &mut stream);
+        assert_eq!(cycle_end(4, &mut stream), CYCLE_END_ACK);
+        put_shut_down(&mut stream);
+    });
+
+    handle.await.unwrap().unwrap();
+    sim.join().unwrap();
+    // only the latest 2 messages are kept by the stream, and none by the queue of get()
+    let cycles: Vec<u32> = probe_stream.map(|msg| msg.cycles).collect().await;
+    assert_eq!(cycles, vec![3, 4]);
+    assert_eq!(server.dropped_messages(0), 3);
+    assert!(server.get(0).await.is_none());
+    assert_eq!(server.get(1).await.unwrap().cycles, 4);
+}
+
 fn u64_from_vec(bytes: Vec<u8>) -> u64 {
     u64::from_le_bytes([
         bytes[0], bytes[1], bytes[2], bytes[3], bytes[4], bytes[5], bytes[6], bytes[7],
@@ -275,6 +1942,53 @@ pub fn put(id: u32, cycles: u32, data: Vec<u8>, stream: &mut UnixStream) {
     thread::sleep(Duration::from_micros(400));
 }
 
+pub fn get(id: u32, cycles: u32, size: usize, stream: &mut UnixStream) -> Vec<u8> {
+    let get_message = GetPutMessage::Get(id, cycles);
+    let serialized = bincode::serialize(&get_message).expect("Serialization failed");
+
+    // The initial 4-byte data specifies the byte count of the message in the u32 format.
+    let msg_size = serialized.len() as MsgSizeType;
+    let mut msg_with_size = Vec::with_capacity(MSG_SIZE_BYTES + serialized.len());
+    msg_with_size.extend_from_slice(msg_size.to_le_bytes().as_slice());
+    msg_with_size.extend(serialized.iter());
+
+    stream
+        .write_all(&msg_with_size)
+        .expect("Failed to write to stream");
+    let mut data = vec![0; size];
+    stream
+        .read_exact(&mut data)
+        .expect("Failed to read from stream");
+    data
+}
+
+pub fn try_get(id: u32, cycles: u32, size: usize, stream: &mut UnixStream) -> Option<Vec<u8>> {
+    let try_get_message = GetPutMessage::TryGet(id, cycles);
+    let serialized = bincode::serialize(&try_get_message).expect("Serialization failed");
+
+    // The initial 4-byte data specifies the byte count of the message in the u32 format.
+    let msg_size = serialized.len() as MsgSizeType;
+    let mut msg_with_size = Vec::with_capacity(MSG_SIZE_BYTES + serialized.len());
+    msg_with_size.extend_from_slice(msg_size.to_le_bytes().as_slice());
+    msg_with_size.extend(serialized.iter());
+
+    stream
+        .write_all(&msg_with_size)
+        .expect("Failed to write to stream");
+    let mut valid = [0];
+    stream
+        .read_exact(&mut valid)
+        .expect("Failed to read from stream");
+    if valid[0] == 0 {
+        return None;
+    }
+    let mut data = vec![0; size];
+    stream
+        .read_exact(&mut data)
+        .expect("Failed to read from stream");
+    Some(data)
+}
+
 pub fn put_data_directly(data: Vec<u8>, stream: &mut UnixStream) {
     // println!("send put");
     thread::sleep(Duration::from_micros(400));
@@ -291,6 +2005,26 @@ pub fn put_data_directly(data: Vec<u8>, stream: &mut UnixStream) {
     thread::sleep(Duration::from_micros(400));
 }
 
+pub fn cycle_end(cycles: u32, stream: &mut UnixStream) -> u8 {
+    let cycle_end_message = GetPutMessage::CycleEnd(cycles);
+    let serialized = bincode::serialize(&cycle_end_message).expect("Serialization failed");
+
+    // The initial 4-byte data specifies the byte count of the message in the u32 format.
+    let msg_size = serialized.len() as MsgSizeType;
+    let mut msg_with_size = Vec::with_capacity(MSG_SIZE_BYTES + serialized.len());
+    msg_with_size.extend_from_slice(msg_size.to_le_bytes().as_slice());
+    msg_with_size.extend(serialized.iter());
+
+    stream
+        .write_all(&msg_with_size)
+        .expect("Failed to write to stream");
+    let mut answer = [0xff];
+    stream
+        .read_exact(&mut answer)
+        .expect("Failed to read from stream");
+    answer[0]
+}
+
 pub fn put_shut_down(stream: &mut UnixStream) {
     thread::sleep(Duration::from_micros(400));
     let put_message = GetPutMessage::ShutDown;
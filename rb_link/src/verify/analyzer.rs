@@ -0,0 +1,512 @@
+use super::Cond;
+use crate::config::ProbeInfo;
+use crate::publisher::{CycleContext, SessionInfo, Subscriber, Summary};
+use crate::server::{B2RMessage, B2RServer, CycleGetter};
+use crate::store::MessageStore;
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::fmt::{self, Write};
+use std::io;
+use std::path::{Path, PathBuf};
+
+type CaptureCallback = Box<dyn FnMut(&Capture) + Send>;
+
+/// The messages captured around a trigger:
+/// - trigger_cycle: the cycle the trigger sequence completed
+/// - messages: the captured messages sorted by the cycles
+/// - complete: false if the simulation ended before the post-trigger window is full
+/// - probes: the descriptions of the probes, for the names and the widths in the VCD
+#[derive(Clone, Debug)]
+pub struct Capture {
+    pub trigger_cycle: u32,
+    pub messages: Vec<B2RMessage>,
+    pub complete: bool,
+    pub probes: HashMap<u32, ProbeInfo>,
+}
+
+impl Capture {
+    /// return the first captured cycle
+    pub fn first_cycle(&self) -> Option<u32> {
+        self.messages.first().map(|message| message.cycles)
+    }
+
+    /// return the last captured cycle
+    pub fn last_cycle(&self) -> Option<u32> {
+        self.messages.last().map(|message| message.cycles)
+    }
+
+    /// return the ids of the captured probes
+    pub fn ids(&self) -> Vec<u32> {
+        let ids: BTreeSet<u32> = self.messages.iter().map(|message| message.id).collect();
+        ids.into_iter().collect()
+    }
+
+    /// Append the captured messages to store, such as a DiskLog to query later.
+    pub fn append_to(&self, store: &mut impl MessageStore) {
+        for message in &self.messages {
+            store.append(message);
+        }
+    }
+
+    /// Write the captured messages in text to path.
+    pub fn write_trace(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        std::fs::write(path, self.to_string())
+    }
+
+    /// Write the capture as a VCD to path.
+    pub fn write_vcd(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        std::fs::write(path, self.to_vcd())
+    }
+
+    /// return the capture as a VCD, a time unit is a cycle.
+    /// Every probe has its payload and a valid signal high in the cycles it sent a message,
+    /// the trigger signal is high in the trigger cycle.
+    pub fn to_vcd(&self) -> String {
+        let ids = self.ids();
+        let mut vcd = String::new();
+        // writing to a String never fails
+        let _ = self.write_vcd_header(&mut vcd, &ids);
+        let mut by_cycle: BTreeMap<u32, Vec<&B2RMessage>> = BTreeMap::new();
+        for message in &self.messages {
+            by_cycle.entry(message.cycles).or_default().push(message);
+        }
+        // the valid signals fall in the cycles after the messages
+        let mut times: BTreeSet<u32> = BTreeSet::new();
+        for cycle in by_cycle.keys().copied().chain([self.trigger_cycle]) {
+            times.insert(cycle);
+            times.insert(cycle.saturating_add(1));
+        }
+        let mut valid = vec![false; ids.len()];
+        let mut triggered = false;
+        for (index, time) in times.into_iter().enumerate() {
+            let first = index == 0;
+            let messages = by_cycle.get(&time);
+            let mut changes = Vec::new();
+            let trigger = time == self.trigger_cycle;
+            if first || trigger != triggered {
+                changes.push(format!("{}{}", trigger as u8, vcd_code(0)));
+                triggered = trigger;
+            }
+            for (probe, id) in ids.iter().enumerate() {
+                let message =
+                    messages.and_then(|messages| messages.iter().find(|message| message.id == *id));
+                match message {
+                    Some(message) => changes.push(format!(
+                        "b{} {}",
+                        vcd_bits(&message.message, self.width(*id)),
+                        vcd_code(2 * probe + 1)
+                    )),
+                    None if first => changes.push(format!("bx {}", vcd_code(2 * probe + 1))),
+                    None => {}
+                }
+                if first || message.is_some() != valid[probe] {
+                    changes.push(format!(
+                        "{}{}",
+                        message.is_some() as u8,
+                        vcd_code(2 * probe + 2)
+                    ));
+                    valid[probe] = message.is_some();
+                }
+            }
+            if changes.is_empty() {
+                continue;
+            }
+            let _ = writeln!(vcd, "#{}", time);
+            if first {
+                let _ = writeln!(vcd, "$dumpvars");
+            }
+            for change in changes {
+                let _ = writeln!(vcd, "{}", change);
+            }
+            if first {
+                let _ = writeln!(vcd, "$end");
+            }
+        }
+        vcd
+    }
+
+    fn write_vcd_header(&self, vcd: &mut String, ids: &[u32]) -> fmt::Result {
+        writeln!(vcd, "$timescale 1 ns $end")?;
+        writeln!(vcd, "$scope module rb_link $end")?;
+        writeln!(vcd, "$var wire 1 {} trigger $end", vcd_code(0))?;
+        for (probe, id) in ids.iter().enumerate() {
+            let name = self.name(*id);
+            let width = self.width(*id);
+            writeln!(
+                vcd,
+                "$var wire {} {} {} $end",
+                width,
+                vcd_code(2 * probe + 1),
+                name
+            )?;
+            writeln!(
+                vcd,
+                "$var wire 1 {} {}_valid $end",
+                vcd_code(2 * probe + 2),
+                name
+            )?;
+        }
+        writeln!(vcd, "$upscope $end")?;
+        writeln!(vcd, "$enddefinitions $end")
+    }
+
+    /// return the name of the probe with id without whitespaces
+    fn name(&self, id: u32) -> String {
+        match self.probes.get(&id) {
+            Some(probe) if !probe.name.is_empty() => probe.name.replace(char::is_whitespace, "_"),
+            _ => format!("probe_{}", id),
+        }
+    }
+
+    /// return the put_width of the probe with id, or the bits of its longest captured payload
+    fn width(&self, id: u32) -> u32 {
+        match self.probes.get(&id) {
+            Some(probe) if probe.put_width > 0 => probe.put_width,
+            _ => self
+                .messages
+                .iter()
+                .filter(|message| message.id == id)
+                .map(|message| message.message.len() as u32 * 8)
+                .max()
+                .unwrap_or_default()
+                .max(1),
+        }
+    }
+}
+
+/// The captured messages, one line per message.
+impl fmt::Display for Capture {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "trigger at cycle {}, {} messages from cycle {:?} to {:?}{}",
+            self.trigger_cycle,
+            self.messages.len(),
+            self.first_cycle(),
+            self.last_cycle(),
+            if self.complete { "" } else { " (truncated)" }
+        )?;
+        for message in &self.messages {
+            writeln!(
+                f,
+                "    cycle {} probe {}: {:?}",
+                message.cycles, message.id, message.message
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// return the VCD identifier of the index-th signal
+fn vcd_code(mut index: usize) -> String {
+    let mut code = String::new();
+    loop {
+        code.push((b'!' + (index % 94) as u8) as char);
+        index /= 94;
+        if index == 0 {
+            return code;
+        }
+    }
+}
+
+/// return the width low bits of the little-endian payload in binary, without the leading zeros
+fn vcd_bits(payload: &[u8], width: u32) -> String {
+    let bits: String = (0..width)
+        .rev()
+        .map(|bit| {
+            let byte = payload.get(bit as usize / 8).copied().unwrap_or_default();
+            if (byte >> (bit % 8)) & 1 == 1 {
+                '1'
+            } else {
+                '0'
+            }
+        })
+        .collect();
+    match bits.trim_start_matches('0') {
+        "" => "0".to_string(),
+        bits => bits.to_string(),
+    }
+}
+
+/// A stage of the trigger sequence, holds after the previous stage,
+/// within cycles after it if within is Some.
+struct Stage {
+    cond: Cond,
+    within: Option<u32>,
+}
+
+/// Capture the messages around a trigger like an integrated logic analyzer,
+/// instead of recording the whole simulation.
+/// The trigger is a sequence of conditions holding in order in increasing cycles (A then B),
+/// the messages of pre_trigger cycles before the trigger are kept in a ring buffer,
+/// and the capture completes post_trigger cycles after the trigger.
+/// Only the cycles with messages are sampled, the depths are measured by the cycle numbers.
+pub struct LogicAnalyzer {
+    stages: Vec<Stage>,
+    pre_trigger: u32,
+    post_trigger: u32,
+    capture_ids: Option<Vec<u32>>,
+    probes: HashMap<u32, ProbeInfo>,
+    buffer: VecDeque<(u32, Vec<B2RMessage>)>,
+    // the next stage and the cycle the previous stage held
+    stage: usize,
+    matched_cycle: Option<u32>,
+    capture: Option<Capture>,
+    delivered: bool,
+    stop_on_capture: bool,
+    vcd_path: Option<PathBuf>,
+    trace_path: Option<PathBuf>,
+    callback: Option<CaptureCallback>,
+    cycle_getter: Option<CycleGetter>,
+}
+
+impl LogicAnalyzer {
+    /// Capture the pre_trigger cycles before the trigger and the post_trigger cycles after it.
+    pub fn new(pre_trigger: u32, post_trigger: u32) -> Self {
+        LogicAnalyzer {
+            stages: Vec::new(),
+            pre_trigger,
+            post_trigger,
+            capture_ids: None,
+            probes: HashMap::new(),
+            buffer: VecDeque::new(),
+            stage: 0,
+            matched_cycle: None,
+            capture: None,
+            delivered: false,
+            stop_on_capture: false,
+            vcd_path: None,
+            trace_path: None,
+            callback: None,
+            cycle_getter: None,
+        }
+    }
+
+    /// Add a stage to the trigger sequence, holding in any cycle after the previous stage.
+    pub fn trigger(&mut self, cond: Cond) {
+        self.stages.push(Stage { cond, within: None });
+    }
+
+    /// Add a stage to the trigger sequence, holding within cycles after the previous stage,
+    /// the sequence restarts if it doesn't.
+    pub fn trigger_within(&mut self, cond: Cond, cycles: u32) {
+        self.stages.push(Stage {
+            cond,
+            within: Some(cycles),
+        });
+    }
+
+    /// Capture only the messages from the probes with ids.
+    /// By default all the messages given to it are captured: all the probes with run(),
+    /// but only the trigger probes as a Subscriber unless it's added with IdFilter::all().
+    pub fn set_capture_ids(&mut self, ids: &[u32]) {
+        self.capture_ids = Some(ids.to_vec());
+    }
+
+    /// Describe a probe for the VCD, the probes of the server or the publisher are added as well.
+    pub fn add_probe(&mut self, info: ProbeInfo) {
+        self.probes.insert(info.id, info);
+    }
+
+    /// Stop Bluesim when the capture completes.
+    pub fn set_stop_on_capture(&mut self, stop: bool) {
+        self.stop_on_capture = stop;
+    }
+
+    /// Write the capture as a VCD to path when it completes or the simulation ends.
+    pub fn set_vcd_path(&mut self, path: impl Into<PathBuf>) {
+        self.vcd_path = Some(path.into());
+    }
+
+    /// Write the captured messages in text to path when the capture completes or the simulation ends.
+    pub fn set_trace_path(&mut self, path: impl Into<PathBuf>) {
+        self.trace_path = Some(path.into());
+    }
+
+    /// Call callback with the capture when it completes or the simulation ends.
+    pub fn on_capture(&mut self, callback: impl FnMut(&Capture) + Send + 'static) {
+        self.callback = Some(Box::new(callback));
+    }
+
+    /// return the ids of the probes of the triggers and the captured probes
+    pub fn ids(&self) -> Vec<u32> {
+        let mut ids: Vec<u32> = self
+            .stages
+            .iter()
+            .flat_map(|stage| stage.cond.ids().to_vec())
+            .chain(self.capture_ids.iter().flatten().copied())
+            .collect();
+        ids.sort_unstable();
+        ids.dedup();
+        ids
+    }
+
+    /// return the capture after the trigger, complete or not
+    pub fn capture(&self) -> Option<&Capture> {
+        self.capture.as_ref()
+    }
+
+    /// Sample the messages of cycle, return true if the capture completes.
+    /// The cycles must be sampled in order, the messages after the capture are ignored.
+    pub fn sample(&mut self, cycle: u32, messages: &[B2RMessage]) -> bool {
+        let captured: Vec<B2RMessage> = messages
+            .iter()
+            .filter(|message| {
+                self.capture_ids
+                    .as_ref()
+                    .is_none_or(|ids| ids.contains(&message.id))
+            })
+            .cloned()
+            .collect();
+        let post_trigger = self.post_trigger;
+        if let Some(capture) = &mut self.capture {
+            if capture.complete {
+                return false;
+            }
+            let end = capture.trigger_cycle.saturating_add(post_trigger);
+            if cycle <= end {
+                capture.messages.extend(captured);
+            }
+            capture.complete = cycle >= end;
+            let complete = capture.complete;
+            if complete {
+                self.deliver();
+            }
+            return complete;
+        }
+        match self.buffer.back_mut() {
+            Some((last, last_messages)) if *last == cycle => last_messages.extend(captured),
+            _ => self.buffer.push_back((cycle, captured)),
+        }
+        while self
+            .buffer
+            .front()
+            .is_some_and(|(front, _)| front.saturating_add(self.pre_trigger) < cycle)
+        {
+            self.buffer.pop_front();
+        }
+        if !self.advance(cycle, messages) {
+            return false;
+        }
+        self.capture = Some(Capture {
+            trigger_cycle: cycle,
+            messages: self
+                .buffer
+                .drain(..)
+                .flat_map(|(_, messages)| messages)
+                .collect(),
+            complete: post_trigger == 0,
+            probes: self.probes.clone(),
+        });
+        if post_trigger == 0 {
+            self.deliver();
+        }
+        post_trigger == 0
+    }
+
+    /// Advance the trigger sequence by a stage at most, return true if the sequence completes.
+    fn advance(&mut self, cycle: u32, messages: &[B2RMessage]) -> bool {
+        let Some(stage) = self.stages.get(self.stage) else {
+            return false;
+        };
+        if let (Some(matched), Some(within)) = (self.matched_cycle, stage.within) {
+            if matched.saturating_add(within) < cycle {
+                self.stage = 0;
+                self.matched_cycle = None;
+            }
+        }
+        if self.matched_cycle == Some(cycle) || !self.stages[self.stage].cond.eval(messages) {
+            return false;
+        }
+        self.stage += 1;
+        self.matched_cycle = Some(cycle);
+        self.stage == self.stages.len()
+    }
+
+    /// Finish the capture at the end of the simulation, return the capture if triggered.
+    /// The capture is truncated if its post-trigger window isn't full.
+    pub fn finish(&mut self) -> Option<&Capture> {
+        self.deliver();
+        self.capture.as_ref()
+    }
+
+    /// Write the capture and call the callback once.
+    fn deliver(&mut self) {
+        let Some(capture) = &self.capture else {
+            return;
+        };
+        if self.delivered {
+            return;
+        }
+        self.delivered = true;
+        // report the errors instead of panicking the publisher
+        if let Some(path) = &self.vcd_path {
+            if let Err(err) = capture.write_vcd(path) {
+                eprintln!("Fail to write the VCD to {}: {}", path.display(), err);
+            }
+        }
+        if let Some(path) = &self.trace_path {
+            if let Err(err) = capture.write_trace(path) {
+                eprintln!("Fail to write the trace to {}: {}", path.display(), err);
+            }
+        }
+        if let Some(callback) = &mut self.callback {
+            callback(capture);
+        }
+    }
+
+    /// Receive the messages of server for run(),
+    /// call it before B2RServer::serve() so the first cycles aren't missed.
+    pub fn attach(&mut self, server: &B2RServer) {
+        self.cycle_getter = Some(CycleGetter::new(server));
+    }
+
+    /// Sample the complete cycles of the attached server until the capture completes
+    /// or Bluesim shuts down the server, return the capture if triggered.
+    /// It blocks the current thread, panics if the analyzer isn't attached by attach().
+    /// Bluesim doesn't wait for it, so it may run a few cycles after the stop is requested.
+    pub fn run(&mut self, server: &mut B2RServer) -> Option<Capture> {
+        for (id, probe) in server.probes() {
+            self.probes.entry(*id).or_insert_with(|| probe.clone());
+        }
+        let mut cycle_getter = self
+            .cycle_getter
+            .take()
+            .expect("Fail to run the analyzer, attach it before B2RServer::serve()");
+        while let Some(messages) = cycle_getter.next_complete_cycle() {
+            let Some(cycle) = messages.first().map(|message| message.cycles) else {
+                continue;
+            };
+            if self.sample(cycle, &messages) {
+                if self.stop_on_capture {
+                    server.request_stop();
+                }
+                break;
+            }
+        }
+        self.finish().cloned()
+    }
+}
+
+impl Subscriber for LogicAnalyzer {
+    /// The probes of the triggers and set_capture_ids(),
+    /// add it with add_subscriber_with(IdFilter::all(), ..) to capture all the probes.
+    fn subscribed_ids(&self) -> Vec<u32> {
+        self.ids()
+    }
+
+    fn on_start(&mut self, session: &SessionInfo) {
+        for (id, probe) in &session.probes {
+            self.probes.entry(*id).or_insert_with(|| probe.clone());
+        }
+    }
+
+    fn update_with_context(&mut self, messages: Vec<B2RMessage>, context: &mut CycleContext) {
+        if self.sample(context.cycle(), &messages) && self.stop_on_capture {
+            context.stop();
+        }
+    }
+
+    fn on_shutdown(&mut self, _summary: &Summary) {
+        self.finish();
+    }
+}
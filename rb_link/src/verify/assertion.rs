@@ -0,0 +1,519 @@
+use super::FromPayload;
+use crate::publisher::{CycleContext, Subscriber, Summary};
+use crate::server::B2RMessage;
+use crate::store::MessageQuery;
+use std::collections::VecDeque;
+use std::fmt;
+use std::ops::{Not, Range};
+use std::sync::Arc;
+
+type CondFn = Arc<dyn Fn(&[B2RMessage]) -> bool + Send + Sync>;
+
+/// A condition over the messages of a cycle, combined by and(), or() and !.
+#[derive(Clone)]
+pub struct Cond {
+    ids: Vec<u32>,
+    eval: CondFn,
+}
+
+impl Cond {
+    /// A condition of the messages from the probes with ids.
+    pub fn new(ids: &[u32], eval: impl Fn(&[B2RMessage]) -> bool + Send + Sync + 'static) -> Self {
+        Cond {
+            ids: ids.to_vec(),
+            eval: Arc::new(eval),
+        }
+    }
+
+    /// true if the probe with id sent a message
+    pub fn fired(id: u32) -> Self {
+        Cond::new(&[id], move |messages| {
+            messages.iter().any(|message| message.id == id)
+        })
+    }
+
+    /// true if the probe with id sent a payload satisfying predicate
+    pub fn payload(id: u32, predicate: impl Fn(&[u8]) -> bool + Send + Sync + 'static) -> Self {
+        Cond::new(&[id], move |messages| {
+            messages
+                .iter()
+                .any(|message| message.id == id && predicate(&message.message))
+        })
+    }
+
+    /// true if the probe with id sent a value satisfying predicate
+    pub fn value<T: FromPayload>(
+        id: u32,
+        predicate: impl Fn(T) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        Cond::payload(id, move |payload| predicate(T::from_payload(payload)))
+    }
+
+    /// true if the fifo probe with id reported full
+    pub fn fifo_full(id: u32) -> Self {
+        Cond::payload(id, |payload| payload.first() == Some(&0))
+    }
+
+    /// true if the fifo probe with id reported empty
+    pub fn fifo_empty(id: u32) -> Self {
+        Cond::payload(id, |payload| payload.get(1) == Some(&0))
+    }
+
+    pub fn and(self, other: Cond) -> Self {
+        let ids = [self.ids.as_slice(), &other.ids].concat();
+        Cond::new(&ids, move |messages| {
+            self.eval(messages) && other.eval(messages)
+        })
+    }
+
+    pub fn or(self, other: Cond) -> Self {
+        let ids = [self.ids.as_slice(), &other.ids].concat();
+        Cond::new(&ids, move |messages| {
+            self.eval(messages) || other.eval(messages)
+        })
+    }
+
+    /// return the condition over the messages of a cycle
+    pub fn eval(&self, messages: &[B2RMessage]) -> bool {
+        (self.eval)(messages)
+    }
+
+    /// return the ids of the probes of the condition
+    pub fn ids(&self) -> &[u32] {
+        &self.ids
+    }
+}
+
+impl Not for Cond {
+    type Output = Cond;
+    fn not(self) -> Cond {
+        let ids = self.ids.clone();
+        Cond::new(&ids, move |messages| !self.eval(messages))
+    }
+}
+
+/// A temporal property:
+/// - Always: cond holds in every cycle
+/// - Within: whenever trigger holds, response holds in the same cycle or the next cycles cycles
+/// - After: whenever trigger holds, response holds exactly cycles cycles later
+/// - AtMost: cond never holds for more than cycles consecutive cycles
+#[derive(Clone)]
+pub enum Property {
+    Always(Cond),
+    Within {
+        trigger: Cond,
+        response: Cond,
+        cycles: u32,
+    },
+    After {
+        trigger: Cond,
+        response: Cond,
+        cycles: u32,
+    },
+    AtMost {
+        cond: Cond,
+        cycles: u32,
+    },
+}
+
+impl Property {
+    pub fn always(cond: Cond) -> Self {
+        Property::Always(cond)
+    }
+
+    pub fn never(cond: Cond) -> Self {
+        Property::Always(!cond)
+    }
+
+    /// trigger |-> ##[0:cycles] response
+    pub fn implies_within(trigger: Cond, response: Cond, cycles: u32) -> Self {
+        Property::Within {
+            trigger,
+            response,
+            cycles,
+        }
+    }
+
+    /// trigger |-> ##cycles response
+    pub fn implies_after(trigger: Cond, response: Cond, cycles: u32) -> Self {
+        Property::After {
+            trigger,
+            response,
+            cycles,
+        }
+    }
+
+    pub fn at_most(cond: Cond, cycles: u32) -> Self {
+        Property::AtMost { cond, cycles }
+    }
+
+    fn ids(&self) -> Vec<u32> {
+        match self {
+            Property::Always(cond) | Property::AtMost { cond, .. } => cond.ids().to_vec(),
+            Property::Within {
+                trigger, response, ..
+            }
+            | Property::After {
+                trigger, response, ..
+            } => [trigger.ids(), response.ids()].concat(),
+        }
+    }
+}
+
+/// A violation of an assertion:
+/// - assertion: the name of the assertion
+/// - cycle: the failing cycle
+/// - reason: what's violated
+/// - history: the messages of the latest cycles when the violation is detected
+#[derive(Clone, Debug)]
+pub struct Violation {
+    pub assertion: String,
+    pub cycle: u32,
+    pub reason: String,
+    pub history: Vec<B2RMessage>,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "assertion {} failed at cycle {}: {}",
+            self.assertion, self.cycle, self.reason
+        )?;
+        for message in &self.history {
+            writeln!(
+                f,
+                "    cycle {} probe {}: {:?}",
+                message.cycles, message.id, message.message
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// The evaluation state of an assertion.
+struct AssertionState {
+    name: String,
+    property: Property,
+    // the trigger cycles waiting for the responses
+    pending: VecDeque<u32>,
+    // the first cycle of the consecutive cycles cond holds, and whether it's reported
+    run: Option<(u32, bool)>,
+    last_cycle: Option<u32>,
+}
+
+impl AssertionState {
+    /// return the failing cycles and the reasons
+    fn check(&mut self, cycle: u32, messages: &[B2RMessage]) -> Vec<(u32, String)> {
+        let mut failures = Vec::new();
+        match &self.property {
+            Property::Always(cond) => {
+                if !cond.eval(messages) {
+                    failures.push((cycle, "the condition doesn't hold".to_string()));
+                }
+            }
+            Property::Within {
+                trigger,
+                response,
+                cycles,
+            } => {
+                while let Some(start) = self.pending.front() {
+                    if start.saturating_add(*cycles) >= cycle {
+                        break;
+                    }
+                    failures.push((start + cycles, within_reason(*start, *cycles)));
+                    self.pending.pop_front();
+                }
+                if response.eval(messages) {
+                    self.pending.clear();
+                } else if trigger.eval(messages) {
+                    self.pending.push_back(cycle);
+                }
+            }
+            Property::After {
+                trigger,
+                response,
+                cycles,
+            } => {
+                while let Some(start) = self.pending.front() {
+                    let due = start.saturating_add(*cycles);
+                    if due > cycle {
+                        break;
+                    }
+                    if due < cycle || !response.eval(messages) {
+                        failures.push((due, after_reason(*start, *cycles)));
+                    }
+                    self.pending.pop_front();
+                }
+                if trigger.eval(messages) {
+                    if *cycles == 0 {
+                        if !response.eval(messages) {
+                            failures.push((cycle, after_reason(cycle, 0)));
+                        }
+                    } else {
+                        self.pending.push_back(cycle);
+                    }
+                }
+            }
+            Property::AtMost { cond, cycles } => {
+                if !cond.eval(messages) {
+                    self.run = None;
+                } else {
+                    let consecutive = self.last_cycle.is_some_and(|last| last + 1 == cycle);
+                    let (start, reported) = match self.run {
+                        Some(run) if consecutive => run,
+                        _ => (cycle, false),
+                    };
+                    let held = cycle - start + 1;
+                    if held > *cycles && !reported {
+                        failures.push((
+                            cycle,
+                            format!(
+                                "the condition holds for more than {} cycles since cycle {}",
+                                cycles, start
+                            ),
+                        ));
+                    }
+                    self.run = Some((start, reported || held > *cycles));
+                }
+            }
+        }
+        self.last_cycle = Some(cycle);
+        failures
+    }
+
+    /// return the triggers without their responses at the end
+    fn finish(&mut self) -> Vec<(u32, String)> {
+        let cycles = match &self.property {
+            Property::Within { cycles, .. } => *cycles,
+            Property::After { cycles, .. } => *cycles,
+            _ => return Vec::new(),
+        };
+        self.pending
+            .drain(..)
+            .map(|start| {
+                let due = start.saturating_add(cycles);
+                (
+                    due,
+                    format!(
+                        "the response for the trigger at cycle {} is missing at the end",
+                        start
+                    ),
+                )
+            })
+            .collect()
+    }
+}
+
+fn within_reason(start: u32, cycles: u32) -> String {
+    format!(
+        "no response within {} cycles after the trigger at cycle {}",
+        cycles, start
+    )
+}
+
+fn after_reason(start: u32, cycles: u32) -> String {
+    format!(
+        "no response {} cycles after the trigger at cycle {}",
+        cycles, start
+    )
+}
+
+/// Check the temporal assertions cycle by cycle on the messages from Bluesim.
+/// Only the cycles with messages from the probes of the assertions are sampled,
+/// the delays are measured by the cycle numbers.
+/// Use it live as a Subscriber, which can stop Bluesim on the first violation,
+/// or offline over a recorded trace.
+pub struct AssertionChecker {
+    assertions: Vec<AssertionState>,
+    history: VecDeque<Vec<B2RMessage>>,
+    history_cycles: usize,
+    violations: Vec<Violation>,
+    stop_on_violation: bool,
+    print_on_shutdown: bool,
+}
+
+impl Default for AssertionChecker {
+    fn default() -> Self {
+        AssertionChecker::new()
+    }
+}
+
+impl AssertionChecker {
+    pub fn new() -> Self {
+        AssertionChecker {
+            assertions: Vec::new(),
+            history: VecDeque::new(),
+            history_cycles: 8,
+            violations: Vec::new(),
+            stop_on_violation: false,
+            print_on_shutdown: false,
+        }
+    }
+
+    /// Add an assertion of property named name.
+    pub fn add(&mut self, name: &str, property: Property) {
+        self.assertions.push(AssertionState {
+            name: name.to_string(),
+            property,
+            pending: VecDeque::new(),
+            run: None,
+            last_cycle: None,
+        });
+    }
+
+    /// Keep the messages of the latest cycles sampled cycles in the violations, 8 by default.
+    pub fn set_history(&mut self, cycles: usize) {
+        self.history_cycles = cycles;
+    }
+
+    /// Stop Bluesim on the first violation when used as a Subscriber.
+    pub fn set_stop_on_violation(&mut self, stop: bool) {
+        self.stop_on_violation = stop;
+    }
+
+    /// Print the violations on shutdown.
+    pub fn set_print_on_shutdown(&mut self, print: bool) {
+        self.print_on_shutdown = print;
+    }
+
+    /// return the ids of the probes of the assertions
+    pub fn ids(&self) -> Vec<u32> {
+        let mut ids: Vec<u32> = self
+            .assertions
+            .iter()
+            .flat_map(|assertion| assertion.property.ids())
+            .collect();
+        ids.sort_unstable();
+        ids.dedup();
+        ids
+    }
+
+    /// Check the messages of cycle, return the new violations.
+    /// The cycles must be checked in order, a cycle without messages from the probes
+    /// of the assertions is not sampled.
+    pub fn check(&mut self, cycle: u32, messages: &[B2RMessage]) -> Vec<Violation> {
+        let ids = self.ids();
+        let messages: Vec<B2RMessage> = messages
+            .iter()
+            .filter(|message| ids.contains(&message.id))
+            .cloned()
+            .collect();
+        if messages.is_empty() {
+            return Vec::new();
+        }
+        if self.history.len() == self.history_cycles {
+            self.history.pop_front();
+        }
+        if self.history_cycles > 0 {
+            self.history.push_back(messages.clone());
+        }
+        let failures: Vec<(String, u32, String)> = self
+            .assertions
+            .iter_mut()
+            .flat_map(|assertion| {
+                let name = assertion.name.clone();
+                assertion
+                    .check(cycle, &messages)
+                    .into_iter()
+                    .map(move |(cycle, reason)| (name.clone(), cycle, reason))
+            })
+            .collect();
+        self.report(failures)
+    }
+
+    /// Finish the check, return the violations of the triggers without their responses.
+    pub fn finish(&mut self) -> Vec<Violation> {
+        let failures: Vec<(String, u32, String)> = self
+            .assertions
+            .iter_mut()
+            .flat_map(|assertion| {
+                let name = assertion.name.clone();
+                assertion
+                    .finish()
+                    .into_iter()
+                    .map(move |(cycle, reason)| (name.clone(), cycle, reason))
+            })
+            .collect();
+        self.report(failures)
+    }
+
+    fn report(&mut self, failures: Vec<(String, u32, String)>) -> Vec<Violation> {
+        let history: Vec<B2RMessage> = self.history.iter().flatten().cloned().collect();
+        let violations: Vec<Violation> = failures
+            .into_iter()
+            .map(|(assertion, cycle, reason)| Violation {
+                assertion,
+                cycle,
+                reason,
+                history: history.clone(),
+            })
+            .collect();
+        self.violations.extend(violations.iter().cloned());
+        violations
+    }
+
+    /// Check a recorded trace of the messages sorted by the cycles, and finish the check.
+    /// return all the violations.
+    pub fn check_trace(
+        &mut self,
+        messages: impl IntoIterator<Item = B2RMessage>,
+    ) -> Vec<Violation> {
+        let mut cycle_messages: Vec<B2RMessage> = Vec::new();
+        for message in messages {
+            if cycle_messages
+                .first()
+                .is_some_and(|first| first.cycles != message.cycles)
+            {
+                self.check(cycle_messages[0].cycles, &cycle_messages);
+                cycle_messages.clear();
+            }
+            cycle_messages.push(message);
+        }
+        if let Some(first) = cycle_messages.first() {
+            self.check(first.cycles, &cycle_messages);
+        }
+        self.finish();
+        self.violations.clone()
+    }
+
+    /// Check the messages stored in cycles through query, and finish the check.
+    /// return all the violations.
+    pub fn check_query(&mut self, query: &MessageQuery, cycles: Range<u32>) -> Vec<Violation> {
+        let ids = self.ids();
+        let mut messages: Vec<B2RMessage> = ids
+            .iter()
+            .flat_map(|id| query.messages(*id, cycles.clone()))
+            .collect();
+        // stable, keeps the order of the ids in a cycle
+        messages.sort_by_key(|message| message.cycles);
+        self.check_trace(messages)
+    }
+
+    /// return all the violations so far
+    pub fn violations(&self) -> &[Violation] {
+        &self.violations
+    }
+}
+
+impl Subscriber for AssertionChecker {
+    fn subscribed_ids(&self) -> Vec<u32> {
+        self.ids()
+    }
+
+    fn update_with_context(&mut self, messages: Vec<B2RMessage>, context: &mut CycleContext) {
+        let violations = self.check(context.cycle(), &messages);
+        if !violations.is_empty() && self.stop_on_violation {
+            context.stop();
+        }
+    }
+
+    fn on_shutdown(&mut self, _summary: &Summary) {
+        self.finish();
+        if self.print_on_shutdown {
+            for violation in &self.violations {
+                print!("{}", violation);
+            }
+        }
+    }
+}
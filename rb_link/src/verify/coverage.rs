@@ -0,0 +1,402 @@
+use super::FromPayload;
+use crate::publisher::{Subscriber, Summary};
+use crate::server::{B2RMessage, R2BMessage};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+use std::io;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+
+/// The values hitting a bin:
+/// - Range: a value in the range
+/// - Values: one of the values, such as the encodings of an enum
+/// - Transition: the consecutive sampled values
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Bin {
+    Range(RangeInclusive<u64>),
+    Values(Vec<u64>),
+    Transition(Vec<u64>),
+}
+
+type DecodeFn = Box<dyn Fn(&[u8]) -> u64 + Send>;
+
+/// A coverage point sampling the payloads of a probe.
+pub struct CoverPoint {
+    name: String,
+    id: u32,
+    decode: DecodeFn,
+    bins: Vec<(String, Bin)>,
+}
+
+impl CoverPoint {
+    /// Sample the payloads of the probe with id, decoded as u64 by default.
+    pub fn new(name: &str, id: u32) -> Self {
+        CoverPoint {
+            name: name.to_string(),
+            id,
+            decode: Box::new(u64::from_payload),
+            bins: Vec::new(),
+        }
+    }
+
+    /// Decode the sampled value from the payload, such as a field of a struct.
+    pub fn decode(mut self, decode: impl Fn(&[u8]) -> u64 + Send + 'static) -> Self {
+        self.decode = Box::new(decode);
+        self
+    }
+
+    /// Add a bin hit by the values in range.
+    pub fn range(self, name: &str, range: RangeInclusive<u64>) -> Self {
+        self.bin(name, Bin::Range(range))
+    }
+
+    /// Add a bin hit by any of values.
+    pub fn values(self, name: &str, values: &[u64]) -> Self {
+        self.bin(name, Bin::Values(values.to_vec()))
+    }
+
+    /// Add a bin hit when the consecutive sampled values are sequence.
+    pub fn transition(self, name: &str, sequence: &[u64]) -> Self {
+        assert!(!sequence.is_empty(), "a transition needs a value");
+        self.bin(name, Bin::Transition(sequence.to_vec()))
+    }
+
+    /// Add a bin, the names of the bins of a point must be unique.
+    pub fn bin(mut self, name: &str, bin: Bin) -> Self {
+        assert!(
+            self.bins.iter().all(|(bin_name, _)| bin_name != name),
+            "duplicate bin {} of {}",
+            name,
+            self.name
+        );
+        self.bins.push((name.to_string(), bin));
+        self
+    }
+}
+
+/// A cover point with its hits and the sampled values for the transitions.
+struct PointState {
+    point: CoverPoint,
+    hits: Vec<u64>,
+    history: VecDeque<u64>,
+    history_len: usize,
+    // the bins hit in the current cycle
+    hit_bins: Vec<usize>,
+}
+
+impl PointState {
+    fn sample(&mut self, value: u64) {
+        if self.history.len() == self.history_len {
+            self.history.pop_front();
+        }
+        self.history.push_back(value);
+        let history = &self.history;
+        self.hit_bins = self
+            .point
+            .bins
+            .iter()
+            .enumerate()
+            .filter(|(_, (_, bin))| match bin {
+                Bin::Range(range) => range.contains(&value),
+                Bin::Values(values) => values.contains(&value),
+                Bin::Transition(sequence) => {
+                    history.len() >= sequence.len()
+                        && history
+                            .iter()
+                            .skip(history.len() - sequence.len())
+                            .eq(sequence.iter())
+                }
+            })
+            .map(|(index, _)| index)
+            .collect();
+        for index in &self.hit_bins {
+            self.hits[*index] += 1;
+        }
+    }
+}
+
+struct Cross {
+    name: String,
+    points: (usize, usize),
+    hits: Vec<u64>,
+}
+
+/// Collect the functional coverage of the probe payloads, like a covergroup.
+/// Every cover point samples the payload of its probe in a cycle,
+/// a cross is hit by the bins of its two points hit in the same cycle.
+pub struct Coverage {
+    points: Vec<PointState>,
+    crosses: Vec<Cross>,
+    print_on_shutdown: bool,
+    json_path: Option<PathBuf>,
+}
+
+impl Default for Coverage {
+    fn default() -> Self {
+        Coverage::new()
+    }
+}
+
+impl Coverage {
+    pub fn new() -> Self {
+        Coverage {
+            points: Vec::new(),
+            crosses: Vec::new(),
+            print_on_shutdown: false,
+            json_path: None,
+        }
+    }
+
+    /// Add a cover point, the names of the points and the crosses must be unique.
+    pub fn add_point(&mut self, point: CoverPoint) {
+        self.assert_unique(&point.name);
+        let history_len = point
+            .bins
+            .iter()
+            .map(|(_, bin)| match bin {
+                Bin::Transition(sequence) => sequence.len(),
+                _ => 1,
+            })
+            .max()
+            .unwrap_or(1);
+        self.points.push(PointState {
+            hits: vec![0; point.bins.len()],
+            point,
+            history: VecDeque::new(),
+            history_len,
+            hit_bins: Vec::new(),
+        });
+    }
+
+    /// Add the cross of the points named a and b, every pair of their bins is a bin of the cross.
+    pub fn add_cross(&mut self, name: &str, a: &str, b: &str) {
+        self.assert_unique(name);
+        let index = |point_name: &str| {
+            self.points
+                .iter()
+                .position(|state| state.point.name == point_name)
+                .unwrap_or_else(|| panic!("no cover point named {}", point_name))
+        };
+        let points = (index(a), index(b));
+        let bins = self.points[points.0].hits.len() * self.points[points.1].hits.len();
+        self.crosses.push(Cross {
+            name: name.to_string(),
+            points,
+            hits: vec![0; bins],
+        });
+    }
+
+    fn assert_unique(&self, name: &str) {
+        assert!(
+            self.points.iter().all(|state| state.point.name != name)
+                && self.crosses.iter().all(|cross| cross.name != name),
+            "duplicate cover point {}",
+            name
+        );
+    }
+
+    /// Print the coverage report on shutdown.
+    pub fn set_print_on_shutdown(&mut self, print: bool) {
+        self.print_on_shutdown = print;
+    }
+
+    /// Write the coverage database in JSON to path on shutdown when used as a Subscriber.
+    pub fn set_json_path(&mut self, path: impl Into<PathBuf>) {
+        self.json_path = Some(path.into());
+    }
+
+    /// return the ids of the sampled probes
+    pub fn ids(&self) -> Vec<u32> {
+        let mut ids: Vec<u32> = self.points.iter().map(|state| state.point.id).collect();
+        ids.sort_unstable();
+        ids.dedup();
+        ids
+    }
+
+    /// Sample the messages of a cycle, such as a Subscriber gets or MessageQuery::at_cycle() returns.
+    /// A point is sampled by the first message from its probe.
+    pub fn sample(&mut self, messages: &[B2RMessage]) {
+        for state in &mut self.points {
+            state.hit_bins.clear();
+            if let Some(message) = messages.iter().find(|message| message.id == state.point.id) {
+                let value = (state.point.decode)(&message.message);
+                state.sample(value);
+            }
+        }
+        for cross in &mut self.crosses {
+            let a = &self.points[cross.points.0];
+            let b = &self.points[cross.points.1];
+            for a_bin in &a.hit_bins {
+                for b_bin in &b.hit_bins {
+                    cross.hits[a_bin * b.hits.len() + b_bin] += 1;
+                }
+            }
+        }
+    }
+
+    /// return the coverage database of the hits so far
+    pub fn database(&self) -> CoverageDb {
+        let mut points: BTreeMap<String, BTreeMap<String, u64>> = BTreeMap::new();
+        for state in &self.points {
+            let bins = state
+                .point
+                .bins
+                .iter()
+                .zip(&state.hits)
+                .map(|((name, _), hits)| (name.clone(), *hits))
+                .collect();
+            points.insert(state.point.name.clone(), bins);
+        }
+        for cross in &self.crosses {
+            let a = &self.points[cross.points.0].point;
+            let b = &self.points[cross.points.1].point;
+            let names = a.bins.iter().flat_map(|(a_name, _)| {
+                b.bins
+                    .iter()
+                    .map(move |(b_name, _)| format!("{} x {}", a_name, b_name))
+            });
+            points.insert(cross.name.clone(), names.zip(cross.hits.clone()).collect());
+        }
+        CoverageDb { runs: 1, points }
+    }
+}
+
+impl Subscriber for Coverage {
+    fn update(&mut self, messages: Vec<B2RMessage>) -> Vec<R2BMessage> {
+        self.sample(&messages);
+        Vec::new()
+    }
+
+    fn subscribed_ids(&self) -> Vec<u32> {
+        self.ids()
+    }
+
+    fn on_shutdown(&mut self, _summary: &Summary) {
+        let database = self.database();
+        if self.print_on_shutdown {
+            print!("{}", database);
+        }
+        if let Some(path) = &self.json_path {
+            // report the error instead of panicking at the end of the run
+            if let Err(err) = database.save(path) {
+                eprintln!(
+                    "Fail to write the coverage database to {}: {}",
+                    path.display(),
+                    err
+                );
+            }
+        }
+    }
+}
+
+/// The hits of the bins of every cover point and cross, merged from runs simulations.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CoverageDb {
+    pub runs: u64,
+    pub points: BTreeMap<String, BTreeMap<String, u64>>,
+}
+
+impl CoverageDb {
+    /// Add the hits of other, the points and the bins only in other are added as well.
+    pub fn merge(&mut self, other: &CoverageDb) {
+        self.runs += other.runs;
+        for (point, bins) in &other.points {
+            let merged = self.points.entry(point.clone()).or_default();
+            for (bin, hits) in bins {
+                *merged.entry(bin.clone()).or_default() += hits;
+            }
+        }
+    }
+
+    /// return the number of the bins hit at least once and the number of all the bins of point,
+    /// or all the points if point is None
+    pub fn covered(&self, point: Option<&str>) -> (usize, usize) {
+        self.points
+            .iter()
+            .filter(|(name, _)| point.is_none_or(|point| point == name.as_str()))
+            .flat_map(|(_, bins)| bins.values())
+            .fold((0, 0), |(covered, total), hits| {
+                (covered + (*hits > 0) as usize, total + 1)
+            })
+    }
+
+    /// return the percentage of the bins hit at least once, of point or all the points
+    pub fn percent(&self, point: Option<&str>) -> f64 {
+        match self.covered(point) {
+            (_, 0) => 0.0,
+            (covered, total) => covered as f64 * 100.0 / total as f64,
+        }
+    }
+
+    /// return the points and the names of their bins never hit
+    pub fn holes(&self) -> Vec<(&str, &str)> {
+        self.points
+            .iter()
+            .flat_map(|(point, bins)| {
+                bins.iter()
+                    .filter(|(_, hits)| **hits == 0)
+                    .map(move |(bin, _)| (point.as_str(), bin.as_str()))
+            })
+            .collect()
+    }
+
+    /// return the database in JSON
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("Fail to serialize coverage database")
+    }
+
+    pub fn from_json(json: &str) -> serde_json::Result<Self> {
+        serde_json::from_str(json)
+    }
+
+    /// Write the database in JSON to path.
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        std::fs::write(path, self.to_json())
+    }
+
+    /// Read a database written by CoverageDb::save().
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let json = std::fs::read_to_string(path)?;
+        CoverageDb::from_json(&json).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Load and merge the databases of several runs.
+    pub fn merge_files<P: AsRef<Path>>(paths: impl IntoIterator<Item = P>) -> io::Result<Self> {
+        let mut merged = CoverageDb::default();
+        for path in paths {
+            merged.merge(&CoverageDb::load(path)?);
+        }
+        Ok(merged)
+    }
+}
+
+/// The coverage report, the percentage of every point and the hits of its bins.
+impl fmt::Display for CoverageDb {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (covered, total) = self.covered(None);
+        writeln!(
+            f,
+            "coverage {:.1}% ({}/{} bins) of {} runs",
+            self.percent(None),
+            covered,
+            total,
+            self.runs
+        )?;
+        for (point, bins) in &self.points {
+            let (covered, total) = self.covered(Some(point));
+            writeln!(
+                f,
+                "{} {:.1}% ({}/{})",
+                point,
+                self.percent(Some(point)),
+                covered,
+                total
+            )?;
+            for (bin, hits) in bins {
+                writeln!(f, "    {:<24} {}", bin, hits)?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,18 @@
+//! Verification components checking the messages from Bluesim.
+//!
+//! Every component can be fed with the messages from the getters or a recorded trace,
+//! or added to B2RPublisher as a Subscriber. The results are returned by the methods
+//! and the callbacks, and formatted by Display. As a Subscriber, a component prints
+//! its report on shutdown only after set_print_on_shutdown(true).
+mod analyzer;
+mod assertion;
+mod coverage;
+mod monitor;
+mod payload;
+mod scoreboard;
+pub use analyzer::*;
+pub use assertion::*;
+pub use coverage::*;
+pub use monitor::*;
+pub use payload::*;
+pub use scoreboard::*;
@@ -0,0 +1,264 @@
+use crate::publisher::{Subscriber, Summary};
+use crate::server::{B2RMessage, R2BMessage};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt;
+use std::hash::Hash;
+
+/// The event of a probe message:
+/// - Start: a transaction with the key starts
+/// - End: the earliest outstanding transaction with the key ends
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TransactionEvent {
+    Start,
+    End,
+}
+
+/// A transaction reconstructed from a start and an end event:
+/// - key: the matching key extracted from the payloads, such as a tag
+/// - start_cycle, end_cycle: the cycles of the start and the end events
+/// - latency: end_cycle - start_cycle
+/// - payloads: the payloads of the start and the end events
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Transaction<K> {
+    pub key: K,
+    pub start_cycle: u32,
+    pub end_cycle: u32,
+    pub latency: u32,
+    pub payloads: (Vec<u8>, Vec<u8>),
+}
+
+type EventFn<K> = Box<dyn Fn(&[u8]) -> Option<(TransactionEvent, K)> + Send>;
+type TransactionCallback<K> = Box<dyn FnMut(&Transaction<K>) + Send>;
+
+/// Reconstruct the transactions from the start and end events sent by one or two probes,
+/// the events are paired by a key extracted from the payloads,
+/// the transactions with the same key end in the order they start.
+pub struct TransactionMonitor<K> {
+    probes: HashMap<u32, EventFn<K>>,
+    outstanding: HashMap<K, VecDeque<(u32, Vec<u8>)>>,
+    outstanding_count: usize,
+    max_outstanding: usize,
+    histogram: BTreeMap<u32, u64>,
+    completed: u64,
+    latency_sum: u64,
+    unmatched_ends: u64,
+    callback: Option<TransactionCallback<K>>,
+    print_on_shutdown: bool,
+}
+
+impl<K: Eq + Hash + Clone> TransactionMonitor<K> {
+    /// Pair the messages of the probe start_id with the later messages of the probe end_id,
+    /// key extracts the matching key from the payloads of both probes.
+    pub fn new(
+        start_id: u32,
+        end_id: u32,
+        key: impl Fn(&[u8]) -> K + Clone + Send + 'static,
+    ) -> Self {
+        TransactionMonitor::with_keys(start_id, key.clone(), end_id, key)
+    }
+
+    /// Like TransactionMonitor::new() with different key extractors for the start and the end payloads.
+    pub fn with_keys(
+        start_id: u32,
+        start_key: impl Fn(&[u8]) -> K + Send + 'static,
+        end_id: u32,
+        end_key: impl Fn(&[u8]) -> K + Send + 'static,
+    ) -> Self {
+        assert_ne!(
+            start_id, end_id,
+            "use TransactionMonitor::single_probe() for the events of one probe"
+        );
+        let mut monitor = TransactionMonitor::empty();
+        monitor.probes.insert(
+            start_id,
+            Box::new(move |payload| Some((TransactionEvent::Start, start_key(payload)))),
+        );
+        monitor.probes.insert(
+            end_id,
+            Box::new(move |payload| Some((TransactionEvent::End, end_key(payload)))),
+        );
+        monitor
+    }
+
+    /// Reconstruct the transactions from the messages of the probe with id,
+    /// classify returns the event and the key of a payload, None to ignore it.
+    pub fn single_probe(
+        id: u32,
+        classify: impl Fn(&[u8]) -> Option<(TransactionEvent, K)> + Send + 'static,
+    ) -> Self {
+        let mut monitor = TransactionMonitor::empty();
+        monitor.probes.insert(id, Box::new(classify));
+        monitor
+    }
+
+    fn empty() -> Self {
+        TransactionMonitor {
+            probes: HashMap::new(),
+            outstanding: HashMap::new(),
+            outstanding_count: 0,
+            max_outstanding: 0,
+            histogram: BTreeMap::new(),
+            completed: 0,
+            latency_sum: 0,
+            unmatched_ends: 0,
+            callback: None,
+            print_on_shutdown: false,
+        }
+    }
+
+    /// Call callback with every completed transaction.
+    pub fn on_transaction(&mut self, callback: impl FnMut(&Transaction<K>) + Send + 'static) {
+        self.callback = Some(Box::new(callback));
+    }
+
+    /// Print the summary and the outstanding transactions on shutdown.
+    pub fn set_print_on_shutdown(&mut self, print: bool) {
+        self.print_on_shutdown = print;
+    }
+
+    /// return the ids of the monitored probes
+    pub fn ids(&self) -> Vec<u32> {
+        let mut ids: Vec<u32> = self.probes.keys().copied().collect();
+        ids.sort_unstable();
+        ids
+    }
+
+    /// Feed a message, return the transaction it ends.
+    /// The messages from the other probes are ignored.
+    pub fn record(&mut self, message: &B2RMessage) -> Option<Transaction<K>> {
+        let (event, key) = self.probes.get(&message.id)?(&message.message)?;
+        match event {
+            TransactionEvent::Start => {
+                self.outstanding
+                    .entry(key)
+                    .or_default()
+                    .push_back((message.cycles, message.message.clone()));
+                self.outstanding_count += 1;
+                self.max_outstanding = self.max_outstanding.max(self.outstanding_count);
+                None
+            }
+            TransactionEvent::End => self.end(key, message),
+        }
+    }
+
+    fn end(&mut self, key: K, message: &B2RMessage) -> Option<Transaction<K>> {
+        let starts = self.outstanding.get_mut(&key);
+        let Some((start_cycle, start_payload)) = starts.and_then(|starts| starts.pop_front())
+        else {
+            self.unmatched_ends += 1;
+            return None;
+        };
+        if self.outstanding.get(&key).is_some_and(VecDeque::is_empty) {
+            self.outstanding.remove(&key);
+        }
+        self.outstanding_count -= 1;
+
+        let latency = message.cycles.saturating_sub(start_cycle);
+        *self.histogram.entry(latency).or_default() += 1;
+        self.completed += 1;
+        self.latency_sum += latency as u64;
+        let transaction = Transaction {
+            key,
+            start_cycle,
+            end_cycle: message.cycles,
+            latency,
+            payloads: (start_payload, message.message.clone()),
+        };
+        if let Some(callback) = &mut self.callback {
+            callback(&transaction);
+        }
+        Some(transaction)
+    }
+
+    /// return the keys and the start cycles of the outstanding transactions, sorted by the start cycles
+    pub fn outstanding(&self) -> Vec<(&K, u32)> {
+        let mut outstanding: Vec<(&K, u32)> = self
+            .outstanding
+            .iter()
+            .flat_map(|(key, starts)| starts.iter().map(move |(cycle, _)| (key, *cycle)))
+            .collect();
+        outstanding.sort_by_key(|(_, cycle)| *cycle);
+        outstanding
+    }
+
+    /// return the number of the outstanding transactions
+    pub fn outstanding_count(&self) -> usize {
+        self.outstanding_count
+    }
+
+    /// return the maximum number of the outstanding transactions at the same time
+    pub fn max_outstanding(&self) -> usize {
+        self.max_outstanding
+    }
+
+    /// return the number of the completed transactions
+    pub fn completed(&self) -> u64 {
+        self.completed
+    }
+
+    /// return the number of the end events without an outstanding transaction
+    pub fn unmatched_ends(&self) -> u64 {
+        self.unmatched_ends
+    }
+
+    /// return the histogram of the latencies, latency -> the number of the transactions
+    pub fn latency_histogram(&self) -> &BTreeMap<u32, u64> {
+        &self.histogram
+    }
+
+    /// return the mean latency of the completed transactions
+    pub fn mean_latency(&self) -> Option<f64> {
+        (self.completed > 0).then(|| self.latency_sum as f64 / self.completed as f64)
+    }
+
+    /// return the maximum latency of the completed transactions
+    pub fn max_latency(&self) -> Option<u32> {
+        self.histogram.keys().next_back().copied()
+    }
+}
+
+/// The counts, the latencies and the outstanding transactions.
+impl<K: Eq + Hash + Clone + fmt::Debug> fmt::Display for TransactionMonitor<K> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} transactions completed, {} outstanding (max {}), {} unmatched ends",
+            self.completed, self.outstanding_count, self.max_outstanding, self.unmatched_ends
+        )?;
+        if let (Some(mean), Some(max)) = (self.mean_latency(), self.max_latency()) {
+            writeln!(f, "latency: mean {:.2}, max {}", mean, max)?;
+        }
+        for (key, cycle) in self.outstanding() {
+            writeln!(f, "outstanding {:?} since cycle {}", key, cycle)?;
+        }
+        Ok(())
+    }
+}
+
+impl<K: Eq + Hash + Clone + fmt::Debug> Subscriber for TransactionMonitor<K> {
+    /// the start events are recorded before the end events of the same cycle,
+    /// so a transaction may start and end in one cycle
+    fn update(&mut self, messages: Vec<B2RMessage>) -> Vec<R2BMessage> {
+        let (starts, ends): (Vec<B2RMessage>, Vec<B2RMessage>) =
+            messages.into_iter().partition(|message| {
+                self.probes
+                    .get(&message.id)
+                    .and_then(|classify| classify(&message.message))
+                    .is_some_and(|(event, _)| event == TransactionEvent::Start)
+            });
+        for message in starts.iter().chain(&ends) {
+            self.record(message);
+        }
+        Vec::new()
+    }
+
+    fn subscribed_ids(&self) -> Vec<u32> {
+        self.ids()
+    }
+
+    fn on_shutdown(&mut self, _summary: &Summary) {
+        if self.print_on_shutdown {
+            print!("{}", self);
+        }
+    }
+}
@@ -0,0 +1,35 @@
+/// Decode the payload of a probe message, the bytes of pack(data) in little endian.
+/// The payloads shorter than the type are zero extended, the longer ones are truncated,
+/// so a Bit#(17) can be decoded as a u32.
+pub trait FromPayload: Sized {
+    fn from_payload(payload: &[u8]) -> Self;
+}
+
+macro_rules! impl_from_payload {
+    ($($t:ty),*) => {
+        $(
+            impl FromPayload for $t {
+                fn from_payload(payload: &[u8]) -> Self {
+                    let mut bytes = [0; std::mem::size_of::<$t>()];
+                    let len = bytes.len().min(payload.len());
+                    bytes[..len].copy_from_slice(&payload[..len]);
+                    <$t>::from_le_bytes(bytes)
+                }
+            }
+        )*
+    };
+}
+
+impl_from_payload!(u8, u16, u32, u64, u128, i8, i16, i32, i64, i128);
+
+impl FromPayload for bool {
+    fn from_payload(payload: &[u8]) -> Self {
+        payload.first().is_some_and(|byte| *byte & 1 != 0)
+    }
+}
+
+impl FromPayload for Vec<u8> {
+    fn from_payload(payload: &[u8]) -> Self {
+        payload.to_vec()
+    }
+}
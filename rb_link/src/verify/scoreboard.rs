@@ -0,0 +1,253 @@
+use super::FromPayload;
+use crate::publisher::{CycleContext, Subscriber, Summary};
+use crate::server::B2RMessage;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+
+/// A DUT output different from the reference model:
+/// - input_cycle: the cycle of the input, None for an input put by Rust or an unexpected output
+/// - output_cycle: the cycle of the output
+/// - expected: the output of the reference model, None if no output is expected
+/// - actual: the output of the DUT
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Mismatch<Out> {
+    pub input_cycle: Option<u32>,
+    pub output_cycle: u32,
+    pub expected: Option<Out>,
+    pub actual: Out,
+}
+
+impl<Out: fmt::Debug> fmt::Display for Mismatch<Out> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.expected, self.input_cycle) {
+            (Some(expected), Some(input_cycle)) => write!(
+                f,
+                "cycle {}: expected {:?} for the input of cycle {}, got {:?}",
+                self.output_cycle, expected, input_cycle, self.actual
+            ),
+            (Some(expected), None) => write!(
+                f,
+                "cycle {}: expected {:?}, got {:?}",
+                self.output_cycle, expected, self.actual
+            ),
+            (None, _) => write!(
+                f,
+                "cycle {}: unexpected output {:?}",
+                self.output_cycle, self.actual
+            ),
+        }
+    }
+}
+
+type ReferenceFn<In, Out> = Box<dyn Fn(In) -> Out + Send>;
+type KeyFns<In, Out> = (
+    Box<dyn Fn(&In) -> u64 + Send>,
+    Box<dyn Fn(&Out) -> u64 + Send>,
+);
+
+/// Compare the outputs of the DUT with a reference model.
+/// Every input is passed to the reference model, and the expected output is compared
+/// with the next output of the DUT, or the next output with the same key out of order.
+pub struct Scoreboard<In, Out> {
+    input_id: Option<u32>,
+    output_id: u32,
+    reference: ReferenceFn<In, Out>,
+    keys: Option<KeyFns<In, Out>>,
+    expected: HashMap<u64, VecDeque<(Option<u32>, Out)>>,
+    pending: usize,
+    matched: u64,
+    mismatches: Vec<Mismatch<Out>>,
+    stop_on_mismatch: bool,
+    print_on_shutdown: bool,
+}
+
+impl<In: FromPayload, Out: FromPayload + PartialEq + Clone> Scoreboard<In, Out> {
+    /// Compare the messages of the probe output_id with the reference of the messages of the probe input_id.
+    pub fn new(
+        input_id: u32,
+        output_id: u32,
+        reference: impl Fn(In) -> Out + Send + 'static,
+    ) -> Self {
+        assert_ne!(
+            input_id, output_id,
+            "the input and the output probes must be different"
+        );
+        let mut scoreboard = Scoreboard::with_inputs(output_id, [], reference);
+        scoreboard.input_id = Some(input_id);
+        scoreboard
+    }
+
+    /// Compare the messages of the probe output_id with the reference of inputs,
+    /// such as the data put into Bluesim by Rust.
+    pub fn with_inputs(
+        output_id: u32,
+        inputs: impl IntoIterator<Item = In>,
+        reference: impl Fn(In) -> Out + Send + 'static,
+    ) -> Self {
+        let mut scoreboard = Scoreboard {
+            input_id: None,
+            output_id,
+            reference: Box::new(reference),
+            keys: None,
+            expected: HashMap::new(),
+            pending: 0,
+            matched: 0,
+            mismatches: Vec::new(),
+            stop_on_mismatch: false,
+            print_on_shutdown: false,
+        };
+        for input in inputs {
+            scoreboard.push_input(None, input);
+        }
+        scoreboard
+    }
+
+    /// Match the outputs out of order, an output is compared with the earliest expected output with the same key.
+    /// input_key and output_key extract the keys, such as a tag, from the inputs and the outputs.
+    /// The expected outputs of the inputs added before, such as by with_inputs(),
+    /// are keyed by output_key of the expected outputs.
+    pub fn out_of_order(
+        &mut self,
+        input_key: impl Fn(&In) -> u64 + Send + 'static,
+        output_key: impl Fn(&Out) -> u64 + Send + 'static,
+    ) {
+        assert!(self.keys.is_none(), "out_of_order is already set");
+        // all the expected outputs are in one queue without the keys
+        let expected = std::mem::take(&mut self.expected);
+        for (cycle, output) in expected.into_values().flatten() {
+            self.expected
+                .entry(output_key(&output))
+                .or_default()
+                .push_back((cycle, output));
+        }
+        self.keys = Some((Box::new(input_key), Box::new(output_key)));
+    }
+
+    /// Stop Bluesim on the first mismatch when used as a Subscriber.
+    pub fn set_stop_on_mismatch(&mut self, stop: bool) {
+        self.stop_on_mismatch = stop;
+    }
+
+    /// Print the matched, mismatched and pending outputs on shutdown.
+    pub fn set_print_on_shutdown(&mut self, print: bool) {
+        self.print_on_shutdown = print;
+    }
+
+    /// Add an input sent at cycle, None for an input put by Rust.
+    pub fn push_input(&mut self, cycle: Option<u32>, input: In) {
+        let key = self
+            .keys
+            .as_ref()
+            .map_or(0, |(input_key, _)| input_key(&input));
+        let expected = (self.reference)(input);
+        self.expected
+            .entry(key)
+            .or_default()
+            .push_back((cycle, expected));
+        self.pending += 1;
+    }
+
+    /// Compare an output sent at cycle, return the mismatch if it's different from the expected output.
+    pub fn push_output(&mut self, cycle: u32, output: Out) -> Option<Mismatch<Out>> {
+        let key = self
+            .keys
+            .as_ref()
+            .map_or(0, |(_, output_key)| output_key(&output));
+        let expected = self.expected.get_mut(&key).and_then(VecDeque::pop_front);
+        if expected.is_some() {
+            self.pending -= 1;
+        }
+        if let Some((_, expected)) = &expected {
+            if *expected == output {
+                self.matched += 1;
+                return None;
+            }
+        }
+        let (input_cycle, expected) = expected.unzip();
+        let mismatch = Mismatch {
+            input_cycle: input_cycle.flatten(),
+            output_cycle: cycle,
+            expected,
+            actual: output,
+        };
+        self.mismatches.push(mismatch.clone());
+        Some(mismatch)
+    }
+
+    /// Feed a message of the input or the output probe, return the mismatch of an output.
+    /// The messages from the other probes are ignored.
+    pub fn record(&mut self, message: &B2RMessage) -> Option<Mismatch<Out>> {
+        if message.id == self.output_id {
+            self.push_output(message.cycles, Out::from_payload(&message.message))
+        } else {
+            if Some(message.id) == self.input_id {
+                self.push_input(Some(message.cycles), In::from_payload(&message.message));
+            }
+            None
+        }
+    }
+
+    /// return the number of the outputs equal to the expected outputs
+    pub fn matched(&self) -> u64 {
+        self.matched
+    }
+
+    /// return the mismatches in the order of the outputs
+    pub fn mismatches(&self) -> &[Mismatch<Out>] {
+        &self.mismatches
+    }
+
+    /// return the number of the expected outputs not compared yet
+    pub fn pending(&self) -> usize {
+        self.pending
+    }
+
+    /// return true if all the expected outputs are compared without a mismatch
+    pub fn passed(&self) -> bool {
+        self.mismatches.is_empty() && self.pending == 0
+    }
+}
+
+/// The counts and the mismatches.
+impl<In, Out: fmt::Debug> fmt::Display for Scoreboard<In, Out> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "scoreboard of probe {}: {} matched, {} mismatched, {} pending",
+            self.output_id,
+            self.matched,
+            self.mismatches.len(),
+            self.pending
+        )?;
+        for mismatch in &self.mismatches {
+            writeln!(f, "{}", mismatch)?;
+        }
+        Ok(())
+    }
+}
+
+impl<In: FromPayload, Out: FromPayload + PartialEq + Clone + fmt::Debug> Subscriber
+    for Scoreboard<In, Out>
+{
+    fn subscribed_ids(&self) -> Vec<u32> {
+        self.input_id.into_iter().chain([self.output_id]).collect()
+    }
+
+    /// the inputs are recorded before the outputs of the same cycle
+    fn update_with_context(&mut self, messages: Vec<B2RMessage>, context: &mut CycleContext) {
+        let (outputs, inputs): (Vec<B2RMessage>, Vec<B2RMessage>) = messages
+            .into_iter()
+            .partition(|message| message.id == self.output_id);
+        for message in inputs.iter().chain(&outputs) {
+            if self.record(message).is_some() && self.stop_on_mismatch {
+                context.stop();
+            }
+        }
+    }
+
+    fn on_shutdown(&mut self, _summary: &Summary) {
+        if self.print_on_shutdown {
+            print!("{}", self);
+        }
+    }
+}